@@ -1,368 +1,4976 @@
-use actix_web::{web, App, HttpServer, HttpResponse, Responder};
+use actix_web::{web, App, HttpRequest, HttpServer, HttpResponse, HttpResponseBuilder, Responder};
+use actix_web::http::header::{self, Header};
+use actix_web::http::StatusCode;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::{from_fn, Compress, Next};
 use serde::{Deserialize, Serialize};
-use reqwest::Client;
-use futures::future::join_all;
+use reqwest::{Client, RequestBuilder};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use futures::stream::{self, StreamExt};
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
 use chrono::{DateTime, Utc};
 use std::time::{Duration, Instant};
 use std::env;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::io::Write;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, LazyLock, Mutex, PoisonError, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use uuid::Uuid;
+use log::{debug, info, warn};
+use sentry::SentryFutureExt;
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+use rand::seq::IndexedRandom;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 
 // The expected token is now loaded from environment variable
 fn get_auth_token() -> String {
-    env::var("AUTH_TOKEN").unwrap_or_else(|_| {
-        eprintln!("WARNING: AUTH_TOKEN environment variable not set, using default value");
+    config_var("AUTH_TOKEN").unwrap_or_else(|| {
+        warn!("AUTH_TOKEN environment variable not set, using default value");
         "secret_token".to_string()
     })
 }
 
-#[derive(Serialize, Clone)]
+// Check the caller's token, from an "Authorization: Bearer" header or the token parameter.
+// The header is checked first, it keeps the secret out of URLs, logs and referrers.
+fn is_authorized(req: &HttpRequest, query_token: Option<&str>) -> bool {
+    let expected = get_auth_token();
+    
+    let bearer = req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim());
+    
+    bearer == Some(expected.as_str()) || query_token == Some(expected.as_str())
+}
+
+// Read an on/off setting from the environment, off unless set to true/1/yes/on
+fn get_env_flag(name: &str) -> bool {
+    matches!(
+        config_var(name).unwrap_or_default().trim().to_ascii_lowercase().as_str(),
+        "true" | "1" | "yes" | "on"
+    )
+}
+
+// Read a numeric setting from the environment, falling back to the default when unset or invalid
+fn get_env_number<T: FromStr + Display + PartialOrd + Default>(name: &str, default: T) -> T {
+    parse_env_number(name, default, false)
+}
+
+// Same as get_env_number, but zero is accepted too, for settings where it means "none" or "off"
+fn get_env_number_or_zero<T: FromStr + Display + PartialOrd + Default>(name: &str, default: T) -> T {
+    parse_env_number(name, default, true)
+}
+
+fn parse_env_number<T: FromStr + Display + PartialOrd + Default>(name: &str, default: T, allow_zero: bool) -> T {
+    match config_var(name) {
+        Some(value) => match value.trim().parse::<T>() {
+            Ok(parsed) if parsed > T::default() || (allow_zero && parsed == T::default()) => parsed,
+            _ => {
+                warn!("Invalid {} value '{}', using default of {}", name, value, default);
+                default
+            }
+        },
+        None => default,
+    }
+}
+
+// Settings read from CONFIG_FILE, KEY=VALUE per line, which take precedence over the environment.
+// Every setting is read through config_var, except CONFIG_FILE itself and RUST_LOG. The file is re-read
+// by POST /api/config/reload, as a running process never sees changes to its own environment, but only
+// the RuntimeConfig settings (and AUTH_TOKEN, read per request) take effect without a restart.
+static CONFIG_FILE_VALUES: LazyLock<RwLock<HashMap<String, String>>> = LazyLock::new(Default::default);
+
+// A setting from CONFIG_FILE, or the environment when the file doesn't set it
+fn config_var(name: &str) -> Option<String> {
+    CONFIG_FILE_VALUES.read().unwrap_or_else(PoisonError::into_inner)
+        .get(name)
+        .cloned()
+        .or_else(|| env::var(name).ok())
+}
+
+// (Re-)read CONFIG_FILE, if set. Blank lines and lines starting with '#' are skipped, values may be quoted.
+fn load_config_file() -> std::io::Result<()> {
+    let Some(path) = env::var("CONFIG_FILE").ok().filter(|p| !p.is_empty()) else {
+        return Ok(());
+    };
+    
+    let values: HashMap<String, String> = std::fs::read_to_string(&path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().trim_matches('"').to_string()))
+        .collect();
+    
+    info!("Loaded {} setting(s) from {}", values.len(), path);
+    *CONFIG_FILE_VALUES.write().unwrap_or_else(PoisonError::into_inner) = values;
+    Ok(())
+}
+
+// Per-request upstream timeout, loaded from REQUEST_TIMEOUT_SECONDS (default 15s).
+// reqwest applies this to each Instagram request and it takes precedence over the
+// client-level timeout set in main, which only acts as a fallback for requests without one.
+fn get_request_timeout() -> Duration {
+    Duration::from_secs(get_env_number("REQUEST_TIMEOUT_SECONDS", 15))
+}
+
+// Maximum number of upstream requests in flight for a single batch, loaded from MAX_CONCURRENCY (default 5)
+fn get_max_concurrency() -> usize {
+    get_env_number("MAX_CONCURRENCY", 5)
+}
+
+// Desktop browser User-Agents used when USER_AGENTS isn't set
+const DEFAULT_USER_AGENTS: &[&str] = &[
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:137.0) Gecko/20100101 Firefox/137.0",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/135.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/135.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:137.0) Gecko/20100101 Firefox/137.0",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/18.3 Safari/605.1.15",
+    "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/135.0.0.0 Safari/537.36",
+];
+
+// User-Agents to rotate through, loaded from USER_AGENTS (newline- or |-separated)
+fn get_user_agents() -> Vec<String> {
+    let user_agents: Vec<String> = config_var("USER_AGENTS")
+        .unwrap_or_default()
+        .split(['\n', '|'])
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+    
+    if user_agents.is_empty() {
+        DEFAULT_USER_AGENTS.iter().map(|s| s.to_string()).collect()
+    } else {
+        user_agents
+    }
+}
+
+// Extra headers for upstream requests from EXTRA_HEADERS, either a JSON object
+// ({"X-CSRFToken": "..."}) or "key:value;key:value" pairs
+fn get_extra_headers() -> HeaderMap {
+    let raw = config_var("EXTRA_HEADERS").unwrap_or_default();
+    let raw = raw.trim();
+    
+    let pairs: Vec<(String, String)> = if raw.starts_with('{') {
+        match serde_json::from_str::<HashMap<String, String>>(raw) {
+            Ok(map) => map.into_iter().collect(),
+            Err(e) => {
+                warn!("Invalid EXTRA_HEADERS JSON, ignoring it: {}", e);
+                Vec::new()
+            }
+        }
+    } else {
+        raw.split(';')
+            .filter_map(|pair| pair.split_once(':'))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .collect()
+    };
+    
+    let mut headers = HeaderMap::new();
+    for (key, value) in pairs {
+        match (HeaderName::from_bytes(key.as_bytes()), HeaderValue::from_str(&value)) {
+            (Ok(name), Ok(value)) => {
+                headers.insert(name, value);
+            }
+            _ => warn!("Ignoring invalid extra header '{}'", key),
+        }
+    }
+    
+    headers
+}
+
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
 struct InstagramPost {
     image_url: String,
+    /// The poster frame of a video post
     video_preview_url: Option<String>,
+    /// The playable MP4 of a video post
+    video_url: Option<String>,
+    shortcode: String,
     direct_link: String,
     date: String,
+    /// Raw taken_at_timestamp (Unix seconds, 0 when unknown), for rendering in any timezone
+    timestamp: i64,
+    /// "image", "video" or "carousel"
+    media_type: String,
+    likes_count: i64,
+    comments_count: i64,
+    caption: String,
+    /// Instagram's auto-generated accessibility caption, null when the node has none
+    alt_text: Option<String>,
+    /// Media size in pixels, for reserving space before the image loads. Null when the node has no dimensions.
+    width: Option<i64>,
+    height: Option<i64>,
+    /// Only set for video posts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    video_view_count: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    video_duration: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    owner_username: Option<String>,
+    /// The CDN URLs behind image_url and video_preview_url when PROXY_IMAGES rewrote them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_image_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_video_preview_url: Option<String>,
+    /// Raw node values for the request's extra_post_fields, by path
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    #[schema(value_type = Object)]
+    extra: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+struct InstagramStory {
+    media_url: String,
+    is_video: bool,
+    expires_at: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+struct Highlight {
+    id: String,
+    title: String,
+    cover_image_url: String,
+}
+
+/// A mention or hashtag in a bio. Offsets are in UTF-16 code units (like JavaScript string indices),
+/// end exclusive, and text is the span as written, including the @ or #.
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+struct BioEntity {
+    /// "mention" or "hashtag"
+    #[serde(rename = "type")]
+    kind: String,
+    text: String,
+    start: usize,
+    end: usize,
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
 struct InstagramUserPosts {
+    user_id: String,
     username: String,
     full_name: String,
     biography: String,
+    /// Mentions and hashtags in the biography, in order
+    bio_entities: Vec<BioEntity>,
+    /// Link in bio, null when the account has none
+    external_url: Option<String>,
     profile_pic_url: String,
+    profile_pic_url_hd: String,
+    /// The CDN URLs behind the profile pictures when PROXY_IMAGES rewrote them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_profile_pic_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_profile_pic_url_hd: Option<String>,
     is_private: bool,
     is_verified: bool,
+    /// Account-age and badge signals, for telling established accounts from fresh ones
+    is_joined_recently: bool,
+    account_badges: Vec<String>,
+    /// Business and creator account details, null (or false) for personal accounts
+    category: Option<String>,
+    is_business: bool,
+    business_email: Option<String>,
+    business_phone: Option<String>,
     followers_count: i64,
     following_count: i64,
     posts_count: i64,
     posts: Vec<InstagramPost>,
+    /// Cursor for the next page of posts, pass it back as "after" to continue
+    end_cursor: Option<String>,
+    has_next_page: bool,
+    stories: Vec<InstagramStory>,
+    highlights: Vec<Highlight>,
+    /// Posts by other accounts the user is tagged in, only fetched with include_tagged
+    tagged_posts: Vec<InstagramPost>,
+    /// The user's reels, only fetched with include_reels
+    reels: Vec<InstagramPost>,
+    /// When this data was fetched from Instagram (RFC3339), carried through the cache
+    fetched_at: String,
+    /// Set when an expired cache entry was served while it's being refreshed
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    stale: bool,
+    /// Set when since_shortcode wasn't in the returned page, so there may be newer posts than it not returned
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    possible_gap: bool,
+    /// Set when this entry couldn't be fetched, e.g. for an invalid username
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
 }
 
-// Cache entry structure to store data with timestamp
-struct CacheEntry {
-    data: InstagramUserPosts,
-    timestamp: Instant,
-}
-
-// App state with in-memory cache
-struct AppState {
-    cache: Mutex<HashMap<String, CacheEntry>>,
-    client: Client,
-}
-
-// Use this structure to parse the endpoint query parameters.
-// It supports both a single username and a comma‑separated list.
-#[derive(Deserialize)]
-struct QueryParams {
-    token: String,
-    // if provided, the "usernames" parameter contains a comma-separated list.
-    usernames: Option<String>,
-    // alternative single username parameter.
-    username: Option<String>,
-}
-
-async fn fetch_instagram_posts(client: &Client, username: &str) -> Result<InstagramUserPosts, reqwest::Error> {
-    // Direct approach to fetch posts without relying on user ID first
-    let url = format!("https://www.instagram.com/api/v1/users/web_profile_info/?username={}", username);
-    
-    println!("Fetching Instagram data for user: {}", username);
-    
-    let resp = client.get(&url)
-        .header("User-Agent", "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:137.0) Gecko/20100101 Firefox/137.0")
-        .header("Accept", "*/*")
-        .header("Accept-Language", "en-US,en;q=0.5")
-        .header("X-IG-App-ID", "936619743392459") // Instagram App ID
-        .header("X-ASBD-ID", "359341")
-        .header("X-IG-WWW-Claim", "0")
-        .header("X-Web-Device-Id", "D08769DB-E84E-4D0D-AF5D-C16D7ED28411") // This could be randomized in production
-        .header("X-Web-Session-ID", "session") // This could be randomized in production
-        .header("X-Requested-With", "XMLHttpRequest")
-        .header("Sec-GPC", "1")
-        .timeout(Duration::from_secs(15))
-        .send()
-        .await?;
-    
-    let status = resp.status();    
-    if !status.is_success() {
-        return Ok(InstagramUserPosts {
+impl InstagramUserPosts {
+    // Blank profile returned when a user couldn't be fetched or parsed
+    fn empty(username: &str) -> Self {
+        InstagramUserPosts {
+            user_id: String::new(),
             username: username.to_string(),
             full_name: String::new(),
             biography: String::new(),
+            bio_entities: Vec::new(),
+            external_url: None,
             profile_pic_url: String::new(),
+            profile_pic_url_hd: String::new(),
+            original_profile_pic_url: None,
+            original_profile_pic_url_hd: None,
             is_private: false,
             is_verified: false,
+            is_joined_recently: false,
+            account_badges: Vec::new(),
+            category: None,
+            is_business: false,
+            business_email: None,
+            business_phone: None,
             followers_count: 0,
             following_count: 0,
             posts_count: 0,
             posts: Vec::new(),
-        });
+            end_cursor: None,
+            has_next_page: false,
+            stories: Vec::new(),
+            highlights: Vec::new(),
+            tagged_posts: Vec::new(),
+            reels: Vec::new(),
+            fetched_at: Utc::now().to_rfc3339(),
+            stale: false,
+            possible_gap: false,
+            error: None,
+        }
     }
     
-    // Get the response body as text first for debugging
-    let body_text = resp.text().await?;
-    
-    // Try to parse the JSON
-    let data = match serde_json::from_str::<serde_json::Value>(&body_text) {
-        Ok(json) => json,
-        Err(_) => {
-            return Ok(InstagramUserPosts {
-                username: username.to_string(),
-                full_name: String::new(),
-                biography: String::new(),
-                profile_pic_url: String::new(),
-                is_private: false,
-                is_verified: false,
-                followers_count: 0,
-                following_count: 0,
-                posts_count: 0,
-                posts: Vec::new(),
-            });
+    // Blank profile carrying an error message for the caller
+    fn failed(username: &str, message: &str) -> Self {
+        InstagramUserPosts {
+            error: Some(message.to_string()),
+            ..InstagramUserPosts::empty(username)
         }
-    };
+    }
+}
+
+// Instagram usernames are 1-30 characters of letters, digits, periods and underscores.
+// Anything else is rejected before it can end up in an upstream URL.
+fn is_valid_username(username: &str) -> bool {
+    (1..=30).contains(&username.len())
+        && username.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_')
+}
+
+// Instagram usernames are case-insensitive, so they're lowercased before they're used as
+// cache keys or sent upstream. "NASA" and "nasa" share one cache entry.
+fn normalize_username(username: &str) -> String {
+    username.trim().to_ascii_lowercase()
+}
+
+// Reasons a profile fetch can fail
+#[derive(Clone, Debug)]
+enum FetchError {
+    // Network failure or timeout while talking to Instagram
+    Request(String),
+    // Instagram answered with an unexpected HTTP status
+    Status(u16),
+    // Instagram answered 429, with how long it asked us to wait if it sent a Retry-After
+    RateLimited(Option<Duration>),
+    // The account doesn't exist
+    NotFound,
+    // The account exists but Instagram won't serve it, e.g. age-gated or temporarily unavailable
+    Restricted(String),
+    // Instagram served a login wall or challenge instead of data, i.e. we're being rate limited
+    Blocked,
+    // The response body wasn't the JSON we expected
+    Parse,
+    // The response body was larger than MAX_RESPONSE_BYTES
+    ResponseTooLarge,
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchError::Request(e) => write!(f, "Request failed: {}", e),
+            FetchError::Status(status) => write!(f, "Unexpected upstream status {}", status),
+            FetchError::RateLimited(_) => write!(f, "Rate limited by Instagram"),
+            FetchError::NotFound => write!(f, "User not found"),
+            FetchError::Restricted(reason) => write!(f, "Account restricted: {}", reason),
+            FetchError::Blocked => write!(f, "Blocked by Instagram"),
+            FetchError::Parse => write!(f, "Could not parse upstream response"),
+            FetchError::ResponseTooLarge => write!(f, "Upstream response too large"),
+        }
+    }
+}
+
+// The error for an unsuccessful upstream response, keeping the Retry-After of a 429
+fn status_error(resp: &reqwest::Response) -> FetchError {
+    if resp.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+        return FetchError::Status(resp.status().as_u16());
+    }
     
-    // Extract user information
-    let user_data = data.get("data").and_then(|d| d.get("user"));
+    FetchError::RateLimited(resp.headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_retry_after))
+}
+
+// Parse a Retry-After value, either delta-seconds or an HTTP-date. Dates in the past mean no wait.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
     
-    // Extract user profile information
-    let full_name = user_data
-        .and_then(|u| u.get("full_name"))
-        .and_then(|v| v.as_str())
-        .unwrap_or("")
-        .to_string();
-        
-    let biography = user_data
-        .and_then(|u| u.get("biography"))
-        .and_then(|v| v.as_str())
-        .unwrap_or("")
-        .to_string();
-        
-    let profile_pic_url = user_data
-        .and_then(|u| u.get("profile_pic_url"))
-        .and_then(|v| v.as_str())
-        .unwrap_or("")
-        .to_string();
-        
-    let is_private = user_data
-        .and_then(|u| u.get("is_private"))
-        .and_then(|v| v.as_bool())
-        .unwrap_or(false);
-        
-    let is_verified = user_data
-        .and_then(|u| u.get("is_verified"))
-        .and_then(|v| v.as_bool())
-        .unwrap_or(false);
-        
-    // Get follower and following counts
-    let followers_count = user_data
-        .and_then(|u| u.get("edge_followed_by"))
-        .and_then(|f| f.get("count"))
-        .and_then(|c| c.as_i64())
-        .unwrap_or(0);
-        
-    let following_count = user_data
-        .and_then(|u| u.get("edge_follow"))
-        .and_then(|f| f.get("count"))
-        .and_then(|c| c.as_i64())
-        .unwrap_or(0);
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((date.with_timezone(&Utc) - Utc::now()).to_std().unwrap_or(Duration::ZERO))
+}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(e: reqwest::Error) -> Self {
+        FetchError::Request(e.to_string())
+    }
+}
+
+// How long a successful fetch stays cached, loaded from CACHE_TTL_SECONDS (default 1h)
+fn get_cache_ttl() -> Duration {
+    Duration::from_secs(get_env_number("CACHE_TTL_SECONDS", 60 * 60))
+}
+
+// TTL for negative results (not-found and private accounts), loaded from NEGATIVE_CACHE_TTL_SECONDS (default 300)
+fn get_negative_cache_ttl() -> Duration {
+    Duration::from_secs(get_env_number("NEGATIVE_CACHE_TTL_SECONDS", 300))
+}
+
+// Cache entry structure to store data with timestamp.
+// Negative results are cached too, with their own (shorter) TTL.
+struct CacheEntry<T = InstagramUserPosts> {
+    data: Result<T, FetchError>,
+    timestamp: Instant,
+    // Wall-clock time of the fetch, reported for cached negative results
+    fetched_at: DateTime<Utc>,
+    ttl: Duration,
+    // Last time this entry was served, gives recently read entries another round before eviction
+    last_accessed: Instant,
+    // Set while a stale-while-revalidate background refresh is in flight
+    refreshing: bool,
+}
+
+impl<T> CacheEntry<T> {
+    fn new(data: Result<T, FetchError>, ttl: Duration) -> Self {
+        let now = Instant::now();
+        CacheEntry {
+            data,
+            timestamp: now,
+            fetched_at: Utc::now(),
+            ttl,
+            last_accessed: now,
+            refreshing: false,
+        }
+    }
     
-    let mut posts = Vec::new();
-    let mut posts_count = 0;
+    fn is_fresh(&self, now: Instant) -> bool {
+        self.is_within(now, Duration::ZERO)
+    }
     
-    // Extract posts from the response based on the actual structure
-    // The structure follows: data.user.edge_owner_to_timeline_media.edges[].node
-    if let Some(user_data) = user_data {
-        if let Some(media) = user_data.get("edge_owner_to_timeline_media") {
-            // Get total posts count
-            posts_count = media.get("count")
-                .and_then(|c| c.as_i64())
-                .unwrap_or(0);
-                
-            if let Some(edges) = media.get("edges") {
-                if let Some(edges_array) = edges.as_array() {                    
-                    for edge in edges_array.iter().take(7) {
-                        if let Some(node) = edge.get("node") {
-                            // Extract image URL
-                            let image_url = node.get("display_url")
-                                .and_then(|v| v.as_str())
-                                .unwrap_or("")
-                                .to_string();
-                            
-                            // Extract video preview if available
-                            let video_preview_url = if node.get("is_video").and_then(|v| v.as_bool()).unwrap_or(false) {
-                                Some(image_url.clone())
-                            } else {
-                                None
-                            };
-                            
-                            // Extract shortcode for direct link
-                            let shortcode = node.get("shortcode")
-                                .and_then(|v| v.as_str())
-                                .unwrap_or("");
-                            
-                            let direct_link = format!("https://www.instagram.com/p/{}/", shortcode);
-                            
-                            // Extract timestamp
-                            let timestamp = node.get("taken_at_timestamp")
-                                .and_then(|v| v.as_i64())
-                                .unwrap_or(0);
-                            
-                            let date = if timestamp > 0 {
-                                DateTime::<Utc>::from_timestamp(timestamp, 0)
-                                    .map(|dt| dt.to_string())
-                                    .unwrap_or_else(|| String::from("Unknown date"))
-                            } else {
-                                String::from("Unknown date")
-                            };
-                            
-                            posts.push(InstagramPost {
-                                image_url,
-                                video_preview_url,
-                                direct_link,
-                                date,
-                            });
-                        }
-                    }
-                }
-            }
-        }
+    // Time left until the entry expires, zero once it has
+    fn remaining_ttl(&self, now: Instant) -> Duration {
+        self.ttl.saturating_sub(now.duration_since(self.timestamp))
     }
     
+    // Whether the entry hasn't been expired for longer than the grace period
+    fn is_within(&self, now: Instant, grace: Duration) -> bool {
+        now.duration_since(self.timestamp) < self.ttl + grace
+    }
     
-    Ok(InstagramUserPosts {
-        username: username.to_string(),
-        full_name,
-        biography,
-        profile_pic_url,
-        is_private,
-        is_verified,
-        followers_count,
-        following_count,
-        posts_count,
-        posts,
-    })
+    // Whether the entry expires within the given window from now
+    fn expires_within(&self, now: Instant, window: Duration) -> bool {
+        now.duration_since(self.timestamp) + window >= self.ttl
+    }
 }
 
-// TypeScript return type:
-// export type InstagramApiResponse = InstagramUserPosts[];
-async fn instagram_handler(query: web::Query<QueryParams>, state: web::Data<Arc<AppState>>) -> impl Responder {
-    // Validate token
-    if query.token != get_auth_token() {
-        return HttpResponse::Unauthorized().body("Invalid token");
+// Concurrent map from cache key to entry. It's sharded internally, so readers of different
+// keys don't contend and there's no single lock to hold (or poison) across a request.
+// Derefs to the map, the insertion order alongside it is only touched by cache_insert.
+struct Cache<T = InstagramUserPosts, K = CacheKey> {
+    entries: DashMap<K, CacheEntry<T>>,
+    // Inserted keys, oldest first, with the entry's timestamp and last_accessed as of queueing.
+    // Keys removed or replaced since stay behind until evicted past or compacted away.
+    order: Mutex<VecDeque<(K, Instant, Instant)>>,
+}
+
+impl<T, K: Eq + Hash> Cache<T, K> {
+    fn new() -> Self {
+        Cache {
+            entries: DashMap::new(),
+            order: Mutex::new(VecDeque::new()),
+        }
     }
+}
 
-    // Determine the list of usernames to query.
-    let usernames: Vec<String> = if let Some(usernames_str) = &query.usernames {
-        usernames_str.split(',')
-            .map(|s| s.trim().to_string())
-            .filter(|s| !s.is_empty())
-            .collect()
-    } else if let Some(username) = &query.username {
-        vec![username.clone()]
-    } else {
-        return HttpResponse::BadRequest().body("No username provided");
-    };
+impl<T, K: Eq + Hash> std::ops::Deref for Cache<T, K> {
+    type Target = DashMap<K, CacheEntry<T>>;
+    
+    fn deref(&self) -> &Self::Target {
+        &self.entries
+    }
+}
 
-    let mut users_posts = Vec::new();
-    let mut usernames_to_fetch = Vec::new();
+// A profile fetch that any number of requests can await for the same result
+type SharedFetch = Shared<BoxFuture<'static, InstagramUserPosts>>;
+
+// Insert into a cache, evicting once it grows past max_entries. Eviction approximates LRU without
+// scanning the cache (CLOCK style): the oldest insert goes first, unless it's been read since it was
+// queued, in which case it goes to the back for another round. Expired entries are still swept
+// separately, this only bounds memory.
+fn cache_insert<T, K: Eq + Hash + Clone>(cache: &Cache<T, K>, key: K, entry: CacheEntry<T>, max_entries: usize) {
+    let mut order = cache.order.lock().unwrap_or_else(PoisonError::into_inner);
+    order.push_back((key.clone(), entry.timestamp, entry.last_accessed));
+    cache.entries.insert(key, entry);
     
-    // Check cache for each username
-    {
-        let cache_lock = &mut state.cache.lock().unwrap();
+    while cache.entries.len() > max_entries {
+        let Some((key, timestamp, last_accessed)) = order.pop_front() else {
+            break;
+        };
         
-        // Set cache expiration time (1 hour)
-        let cache_expiry = Duration::from_secs(60 * 60);
-        let now = Instant::now();
+        // Skip keys that are gone or were replaced, a replacement is queued separately
+        let accessed = match cache.entries.get(&key) {
+            Some(entry) if entry.timestamp == timestamp => entry.last_accessed,
+            _ => continue,
+        };
         
-        // Remove expired entries while we're at it
-        cache_lock.retain(|_, entry| now.duration_since(entry.timestamp) < cache_expiry);
+        if accessed > last_accessed {
+            order.push_back((key, timestamp, accessed));
+        } else {
+            cache.entries.remove_if(&key, |_, entry| entry.timestamp == timestamp);
+        }
+    }
+    
+    // Drop queued keys that were swept or replaced, once they outnumber the live ones
+    if order.len() > max_entries.saturating_mul(2).max(64) {
+        order.retain(|(key, timestamp, _)| cache.entries.get(key).is_some_and(|entry| entry.timestamp == *timestamp));
+    }
+}
+
+// How expired cache entries are handled, from CACHE_MODE
+#[derive(Clone, Copy, PartialEq)]
+enum CacheMode {
+    // Expired entries are re-fetched before responding
+    Standard,
+    // Expired entries are served immediately (flagged as stale) and refreshed in the background
+    StaleWhileRevalidate,
+}
+
+fn get_cache_mode() -> CacheMode {
+    match config_var("CACHE_MODE").unwrap_or_default().trim().to_ascii_lowercase().as_str() {
+        "swr" => CacheMode::StaleWhileRevalidate,
+        "" | "standard" => CacheMode::Standard,
+        other => {
+            warn!("Unknown CACHE_MODE '{}', using standard caching", other);
+            CacheMode::Standard
+        }
+    }
+}
+
+/// Circuit breaker state, as reported on /health
+#[derive(Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum CircuitStatus {
+    /// Upstream requests go through normally
+    Closed,
+    /// Too many blocks in a row, upstream requests fail immediately until the cooldown ends
+    Open,
+    /// Cooldown over, a single probe request decides whether to close or re-open
+    HalfOpen,
+}
+
+#[derive(Default)]
+struct CircuitState {
+    consecutive_failures: u32,
+    // Start of the current run of failures, older runs don't count towards the threshold
+    first_failure: Option<Instant>,
+    opened_at: Option<Instant>,
+    // When the half-open probe was let through, if one is in flight
+    probe_started: Option<Instant>,
+}
+
+// Stops us from hammering Instagram while it's blocking us. After `threshold` blocks or 429s
+// within `window` the circuit opens and upstream fetches fail fast for `cooldown`, after which
+// one probe request is let through to test the water.
+struct CircuitBreaker {
+    state: Mutex<CircuitState>,
+    threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    fn new(threshold: u32, window: Duration, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            state: Mutex::new(CircuitState::default()),
+            threshold,
+            window,
+            cooldown,
+        }
+    }
+    
+    fn lock(&self) -> std::sync::MutexGuard<'_, CircuitState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    
+    fn status(&self) -> CircuitStatus {
+        match self.lock().opened_at {
+            Some(opened_at) if opened_at.elapsed() < self.cooldown => CircuitStatus::Open,
+            Some(_) => CircuitStatus::HalfOpen,
+            None => CircuitStatus::Closed,
+        }
+    }
+    
+    // Seconds until an open circuit lets a probe through
+    fn cooldown_remaining(&self) -> Option<u64> {
+        self.lock().opened_at
+            .map(|opened_at| self.cooldown.saturating_sub(opened_at.elapsed()).as_secs())
+            .filter(|&secs| secs > 0)
+    }
+    
+    fn consecutive_failures(&self) -> u32 {
+        self.lock().consecutive_failures
+    }
+    
+    // Whether an upstream request may go out now. In the half-open state only one probe is
+    // allowed at a time, a probe that never reports back is given up on after another cooldown.
+    fn allow_request(&self) -> bool {
+        let mut state = self.lock();
         
-        // Check for cached entries
-        for username in &usernames {
-            if let Some(entry) = cache_lock.get(username) {
-                if now.duration_since(entry.timestamp) < cache_expiry {
-                    // Cache hit
-                    println!("Cache hit for user: {}", username);
-                    users_posts.push(entry.data.clone());
-                } else {
-                    // Cache expired
-                    usernames_to_fetch.push(username.clone());
+        match state.opened_at {
+            None => true,
+            Some(opened_at) if opened_at.elapsed() < self.cooldown => false,
+            Some(_) => match state.probe_started {
+                Some(started) if started.elapsed() < self.cooldown => false,
+                _ => {
+                    info!("Circuit half-open, sending a probe request to Instagram");
+                    state.probe_started = Some(Instant::now());
+                    true
                 }
-            } else {
-                // Cache miss
-                usernames_to_fetch.push(username.clone());
-            }
+            },
         }
     }
     
-    // Fetch data for uncached usernames
-    if !usernames_to_fetch.is_empty() {
-        // Process each username concurrently.
-        let fetches = usernames_to_fetch.iter()
-            .map(|uname| fetch_instagram_posts(&state.client, uname));
-        let results = join_all(fetches).await;
-        
-        let cache_lock = &mut state.cache.lock().unwrap();
+    // Feed an upstream result into the breaker. Only blocks and 429s count as failures,
+    // any other answer means Instagram is talking to us again.
+    fn record<T>(&self, result: &Result<T, FetchError>) {
+        let mut state = self.lock();
         
-        // Process results and update cache
-        for (i, res) in results.into_iter().enumerate() {
-            let username = &usernames_to_fetch[i];
-            
-            match res {
-                Ok(data) => {
-                    // Update cache
-                    cache_lock.insert(username.clone(), CacheEntry {
-                        data: data.clone(),
-                        timestamp: Instant::now(),
-                    });
-                    users_posts.push(data);
-                },
-                Err(_) => {
-                    let empty_data = InstagramUserPosts { 
-                        username: username.clone(),
-                        full_name: String::new(),
-                        biography: String::new(),
-                        profile_pic_url: String::new(),
-                        is_private: false,
-                        is_verified: false,
-                        followers_count: 0,
-                        following_count: 0,
-                        posts_count: 0,
-                        posts: vec![] 
-                    };
-                    
-                    users_posts.push(empty_data);
+        match result {
+            Err(FetchError::Blocked) | Err(FetchError::RateLimited(_)) => {
+                let now = Instant::now();
+                
+                if state.probe_started.is_some() {
+                    warn!("Circuit probe was blocked, re-opening for {}s", self.cooldown.as_secs());
+                    state.opened_at = Some(now);
+                    state.probe_started = None;
+                    state.consecutive_failures += 1;
+                    return;
+                }
+                
+                match state.first_failure {
+                    Some(first) if now.duration_since(first) <= self.window => state.consecutive_failures += 1,
+                    _ => {
+                        state.first_failure = Some(now);
+                        state.consecutive_failures = 1;
+                    }
+                }
+                
+                if state.opened_at.is_none() && state.consecutive_failures >= self.threshold {
+                    warn!(
+                        "Instagram blocked {} requests in a row, opening circuit for {}s",
+                        state.consecutive_failures,
+                        self.cooldown.as_secs()
+                    );
+                    state.opened_at = Some(now);
+                }
+            }
+            _ => {
+                if state.opened_at.is_some() {
+                    info!("Instagram is responding again, closing circuit");
                 }
+                *state = CircuitState::default();
             }
         }
     }
+}
 
-    HttpResponse::Ok().json(users_posts)
+// Which queue upstream requests wait in for a slot
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Priority {
+    // Requests someone is waiting on
+    High,
+    // Cache warming and webhook polling
+    Low,
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    println!("Starting Instagram API server on http://0.0.0.0:8080");
+// Caps how many requests to Instagram are in flight at once. Freed slots go to waiting
+// high-priority ones first, so background work can't hold up interactive requests.
+struct UpstreamQueue {
+    state: Mutex<QueueState>,
+    slots: usize,
+}
+
+struct QueueState {
+    available: usize,
+    // Waiters by priority, woken in order. A sender whose receiver is gone is skipped.
+    high: VecDeque<oneshot::Sender<()>>,
+    low: VecDeque<oneshot::Sender<()>>,
+}
+
+impl UpstreamQueue {
+    fn new(slots: usize) -> Self {
+        UpstreamQueue {
+            state: Mutex::new(QueueState {
+                available: slots,
+                high: VecDeque::new(),
+                low: VecDeque::new(),
+            }),
+            slots,
+        }
+    }
     
-    // Initialize client
-    let client = Client::builder()
-        .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64)")
-        .timeout(Duration::from_secs(30))
+    fn lock(&self) -> std::sync::MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    
+    // Wait for a slot. A free one is only taken right away when nobody of the same or higher priority is queued.
+    async fn acquire(&self, priority: Priority) -> UpstreamSlot<'_> {
+        let rx = {
+            let mut state = self.lock();
+            let queued_ahead = match priority {
+                Priority::High => !state.high.is_empty(),
+                Priority::Low => !state.high.is_empty() || !state.low.is_empty(),
+            };
+            
+            if state.available > 0 && !queued_ahead {
+                state.available -= 1;
+                return UpstreamSlot { queue: self };
+            }
+            
+            let (tx, rx) = oneshot::channel();
+            match priority {
+                Priority::High => state.high.push_back(tx),
+                Priority::Low => state.low.push_back(tx),
+            }
+            rx
+        };
+        
+        let mut waiter = QueueWaiter { queue: self, rx };
+        // Senders are only ever dropped after sending, so this can't fail
+        let _ = (&mut waiter.rx).await;
+        UpstreamSlot { queue: self }
+    }
+    
+    // Hand a freed slot to the next waiter, high priority first, or put it back
+    fn release(&self) {
+        let mut state = self.lock();
+        while let Some(tx) = state.high.pop_front().or_else(|| state.low.pop_front()) {
+            if tx.send(()).is_ok() {
+                return;
+            }
+        }
+        state.available += 1;
+    }
+    
+    fn queued(&self) -> (usize, usize) {
+        let state = self.lock();
+        (state.high.len(), state.low.len())
+    }
+}
+
+// A held upstream slot, released when dropped
+struct UpstreamSlot<'a> {
+    queue: &'a UpstreamQueue,
+}
+
+impl Drop for UpstreamSlot<'_> {
+    fn drop(&mut self) {
+        self.queue.release();
+    }
+}
+
+// A fetch waiting in the queue. If it's abandoned (the client went away) just as a slot was
+// handed to it, the slot is passed on instead of being lost.
+struct QueueWaiter<'a> {
+    queue: &'a UpstreamQueue,
+    rx: oneshot::Receiver<()>,
+}
+
+impl Drop for QueueWaiter<'_> {
+    fn drop(&mut self) {
+        self.rx.close();
+        if self.rx.try_recv().is_ok() {
+            self.queue.release();
+        }
+    }
+}
+
+// App state with in-memory cache
+struct AppState {
+    cache: Cache,
+    // Single posts looked up by shortcode
+    post_cache: Cache<InstagramPost, String>,
+    // Hashtag and location feeds, keyed like "tag:travel:7" or "location:123:7"
+    feed_cache: Cache<Vec<InstagramPost>, String>,
+    client: Client,
+    // Off with CACHE_ENABLED=false: every request is fetched fresh and nothing is cached
+    cache_enabled: bool,
+    // Settings swapped out by POST /api/config/reload, read through runtime()
+    runtime: RwLock<Arc<RuntimeConfig>>,
+    cache_mode: CacheMode,
+    // How long past expiry an entry may still be served in stale-while-revalidate mode
+    max_stale: Duration,
+    // Upper bound on entries per cache, the least recently used are evicted beyond it
+    max_cache_entries: usize,
+    // User-Agents picked from at random for each upstream request
+    user_agents: Vec<String>,
+    // Headers added to (or overriding the defaults of) every upstream request
+    extra_headers: HeaderMap,
+    // Logged-in session cookie from SESSION_COOKIE, required for stories and the highlights tray
+    session_cookie: Option<String>,
+    // Scrape the profile page when the API is blocked, from HTML_FALLBACK
+    html_fallback: bool,
+    circuit_breaker: CircuitBreaker,
+    // Prioritized slots for requests to Instagram, sized from UPSTREAM_CONCURRENCY
+    upstream_queue: UpstreamQueue,
+    // Whether raw=true may return Instagram's unprocessed user object, from ALLOW_RAW
+    allow_raw: bool,
+    // Set when PROXY_IMAGES is on: where rewritten image URLs point, from PUBLIC_BASE_URL ("" for relative URLs)
+    image_proxy_base_url: Option<String>,
+    // Upstream bodies larger than this are abandoned, from MAX_RESPONSE_BYTES
+    max_response_bytes: usize,
+    // Limits how many posts requests are handled at once, sized from MAX_INFLIGHT
+    inflight: Arc<Semaphore>,
+    // Profile fetches currently underway, joined by concurrent misses for the same key
+    in_flight: DashMap<FetchKey, SharedFetch>,
+    // Latest X-IG-WWW-Claim handed out by Instagram, echoed back like a browser session would ("0" until we get one)
+    www_claim: RwLock<String>,
+    // Well-known public account /api/selftest scrapes, from SELFTEST_USERNAME
+    selftest_username: String,
+    // The only usernames that may be fetched when ALLOWED_USERNAMES is set
+    allowed_usernames: Option<HashSet<String>>,
+    // Substituted for empty profile picture URLs, from DEFAULT_AVATAR_URL
+    default_avatar_url: Option<String>,
+    // Instance to borrow profiles from while we're blocked, from BACKUP_UPSTREAM_URL
+    backup_upstream: Option<BackupUpstream>,
+    // How many times a 429 is retried, from RATE_LIMIT_RETRIES (default 0, no retries)
+    rate_limit_retries: u32,
+    // Longest Retry-After we'll wait out, from MAX_RETRY_AFTER_SECONDS
+    max_retry_after: Duration,
+    // Where upstream requests go, from INSTAGRAM_BASE_URL without a trailing slash
+    instagram_base_url: String,
+    // Last rate-limit headers seen on an upstream response, reported by /health
+    upstream_rate_limit: RwLock<Option<UpstreamRateLimit>>,
+    // Cumulative posts requests and profile cache hits (stale ones included) and misses, for the stats log
+    posts_requests: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+// Largest upstream timeout a request may ask for with timeout_ms
+const MAX_TIMEOUT_MS: u64 = 30_000;
+
+// Settings that can be changed without a restart through POST /api/config/reload (after editing CONFIG_FILE):
+// CACHE_TTL_SECONDS, CACHE_TTL_JITTER_SECONDS, NEGATIVE_CACHE_TTL_SECONDS, REQUEST_TIMEOUT_SECONDS, MAX_CONCURRENCY,
+// MAX_USERNAMES_PER_REQUEST, SLOW_REQUEST_MS, WARM_USERNAMES, DEFAULT_POST_LIMIT and MAX_POST_LIMIT.
+// Everything else is only read at startup.
+struct RuntimeConfig {
+    cache_ttl: Duration,
+    // Random spread applied to every cache TTL, from CACHE_TTL_JITTER_SECONDS (0 turns it off)
+    cache_ttl_jitter: Duration,
+    negative_cache_ttl: Duration,
+    request_timeout: Duration,
+    max_concurrency: usize,
+    max_usernames_per_request: usize,
+    // Batches slower than this are logged
+    slow_request_threshold: Duration,
+    // Hot accounts the cache warmer keeps cached
+    warm_usernames: Vec<String>,
+    // Posts returned per user when the request doesn't specify a limit, never more than the maximum
+    default_post_limit: usize,
+    // Largest post limit a request may ask for
+    max_post_limit: usize,
+}
+
+impl RuntimeConfig {
+    fn load() -> Self {
+        let max_post_limit = get_env_number("MAX_POST_LIMIT", 50);
+        
+        RuntimeConfig {
+            cache_ttl: get_cache_ttl(),
+            cache_ttl_jitter: Duration::from_secs(get_env_number_or_zero("CACHE_TTL_JITTER_SECONDS", 300)),
+            negative_cache_ttl: get_negative_cache_ttl(),
+            request_timeout: get_request_timeout(),
+            max_concurrency: get_max_concurrency(),
+            max_usernames_per_request: get_env_number("MAX_USERNAMES_PER_REQUEST", 20),
+            slow_request_threshold: Duration::from_millis(get_env_number("SLOW_REQUEST_MS", 5000)),
+            warm_usernames: config_var("WARM_USERNAMES")
+                .unwrap_or_default()
+                .split(',')
+                .map(normalize_username)
+                .filter(|s| is_valid_username(s))
+                .collect(),
+            default_post_limit: get_env_number("DEFAULT_POST_LIMIT", 7).min(max_post_limit),
+            max_post_limit,
+        }
+    }
+    
+    // The number of posts to return for a request's limit parameter
+    fn post_limit(&self, requested: Option<usize>) -> usize {
+        requested.unwrap_or(self.default_post_limit).min(self.max_post_limit)
+    }
+    
+    fn log(&self) {
+        info!("Cache TTL: {}s (±{}s jitter)", self.cache_ttl.as_secs(), self.cache_ttl_jitter.as_secs());
+        info!("Negative cache TTL: {}s", self.negative_cache_ttl.as_secs());
+        info!("Upstream request timeout: {}s", self.request_timeout.as_secs());
+        info!("Max concurrent upstream requests per batch: {}", self.max_concurrency);
+        info!("Max usernames per request: {}", self.max_usernames_per_request);
+        info!("Logging requests slower than {}ms", self.slow_request_threshold.as_millis());
+        info!("Post limit: {} by default, at most {}", self.default_post_limit, self.max_post_limit);
+        if !self.warm_usernames.is_empty() {
+            info!("Keeping {} account(s) warm in the cache", self.warm_usernames.len());
+        }
+    }
+}
+
+impl AppState {
+    // How long a fetch result should be cached for, if at all.
+    // Private, restricted and not-found accounts get the shorter negative TTL, transient failures aren't cached.
+    fn cache_ttl_for(&self, result: &Result<InstagramUserPosts, FetchError>) -> Option<Duration> {
+        match result {
+            Ok(data) if data.is_private => Some(self.runtime().negative_cache_ttl),
+            Ok(_) => Some(self.runtime().cache_ttl),
+            Err(FetchError::NotFound | FetchError::Restricted(_)) => Some(self.runtime().negative_cache_ttl),
+            Err(_) => None,
+        }
+        .map(|ttl| self.jittered(ttl))
+    }
+    
+    // Shift a TTL by a random offset within CACHE_TTL_JITTER_SECONDS either way, so entries cached
+    // together don't all expire (and get refreshed) at the same moment. Capped at half the TTL.
+    fn jittered(&self, ttl: Duration) -> Duration {
+        let max_offset = self.runtime().cache_ttl_jitter.min(ttl / 2).as_millis() as i64;
+        if max_offset == 0 {
+            return ttl;
+        }
+        
+        let offset = rand::random_range(-max_offset..=max_offset);
+        Duration::from_millis((ttl.as_millis() as i64 + offset) as u64)
+    }
+    
+    // The current hot-reloadable settings
+    fn runtime(&self) -> Arc<RuntimeConfig> {
+        self.runtime.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+    
+    // How long expired entries are kept around to be served stale
+    fn stale_grace(&self) -> Duration {
+        match self.cache_mode {
+            CacheMode::Standard => Duration::ZERO,
+            CacheMode::StaleWhileRevalidate => self.max_stale,
+        }
+    }
+}
+
+// Optional extras requested alongside a profile fetch
+#[derive(Clone)]
+struct FetchOptions {
+    // maximum number of posts to return
+    limit: usize,
+    include_stories: bool,
+    include_highlights: bool,
+    include_tagged: bool,
+    include_reels: bool,
+    // pagination cursor from a previous response's end_cursor
+    after: Option<String>,
+    // only return posts from the last this many days
+    max_age_days: Option<u32>,
+    // node paths copied verbatim into each post's extra map
+    extra_post_fields: Vec<String>,
+    // per-request override of the upstream timeout, part of the fetch key but not the cache key
+    timeout: Option<Duration>,
+    // whether a blocked fetch may fall back on BACKUP_UPSTREAM_URL, off for requests that came from another instance
+    allow_backup: bool,
+    // place in the upstream queue, high unless it's background work
+    priority: Priority,
+}
+
+impl FetchOptions {
+    // Default options, with the currently configured post limit
+    fn new(state: &AppState) -> Self {
+        FetchOptions {
+            limit: state.runtime().default_post_limit,
+            include_stories: false,
+            include_highlights: false,
+            include_tagged: false,
+            include_reels: false,
+            after: None,
+            max_age_days: None,
+            extra_post_fields: Vec::new(),
+            timeout: None,
+            allow_backup: true,
+            priority: Priority::High,
+        }
+    }
+    
+    // Default options for fetches nobody is waiting on, which yield to interactive ones
+    fn background(state: &AppState) -> Self {
+        FetchOptions {
+            priority: Priority::Low,
+            ..FetchOptions::new(state)
+        }
+    }
+}
+
+// Profile cache key: the username plus every option that changes the returned payload,
+// so requests with different options never get each other's data
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    username: String,
+    limit: usize,
+    include_stories: bool,
+    include_highlights: bool,
+    include_tagged: bool,
+    include_reels: bool,
+    after: Option<String>,
+    max_age_days: Option<u32>,
+    extra_post_fields: Vec<String>,
+}
+
+impl CacheKey {
+    fn new(username: &str, options: &FetchOptions) -> Self {
+        CacheKey {
+            username: username.to_string(),
+            limit: options.limit,
+            include_stories: options.include_stories,
+            include_highlights: options.include_highlights,
+            include_tagged: options.include_tagged,
+            include_reels: options.include_reels,
+            after: options.after.clone(),
+            max_age_days: options.max_age_days,
+            extra_post_fields: options.extra_post_fields.clone(),
+        }
+    }
+    
+    // Shown in the cache summary: the bare username for default options, otherwise the non-default ones
+    // appended query-string style, e.g. "nasa?limit=12&stories"
+    fn describe(&self, default_post_limit: usize) -> String {
+        let mut extras = Vec::new();
+        if self.limit != default_post_limit {
+            extras.push(format!("limit={}", self.limit));
+        }
+        if self.include_stories {
+            extras.push("stories".to_string());
+        }
+        if self.include_highlights {
+            extras.push("highlights".to_string());
+        }
+        if self.include_tagged {
+            extras.push("tagged".to_string());
+        }
+        if self.include_reels {
+            extras.push("reels".to_string());
+        }
+        if let Some(after) = &self.after {
+            extras.push(format!("after={}", after));
+        }
+        if let Some(max_age_days) = self.max_age_days {
+            extras.push(format!("max_age_days={}", max_age_days));
+        }
+        if !self.extra_post_fields.is_empty() {
+            extras.push(format!("extra_post_fields={}", self.extra_post_fields.join(",")));
+        }
+        
+        if extras.is_empty() {
+            self.username.clone()
+        } else {
+            format!("{}?{}", self.username, extras.join("&"))
+        }
+    }
+}
+
+// Key for joining an in-flight fetch: the cache key plus the options that change how the fetch is made.
+// A request forwarded from a backup peer mustn't join a fetch that may itself be waiting on that peer,
+// and a request with its own timeout_ms mustn't inherit another fetch's timeout.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct FetchKey {
+    cache_key: CacheKey,
+    allow_backup: bool,
+    timeout: Option<Duration>,
+}
+
+impl FetchKey {
+    fn new(username: &str, options: &FetchOptions) -> Self {
+        FetchKey {
+            cache_key: CacheKey::new(username, options),
+            allow_backup: options.allow_backup,
+            timeout: options.timeout,
+        }
+    }
+}
+
+// A list of names given either as a comma-separated string (query string) or a JSON array (POST body)
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NameList {
+    Joined(String),
+    List(Vec<String>),
+}
+
+impl NameList {
+    // Joined lists may be separated by commas, newlines or spaces, none of which can appear in a name
+    fn to_vec(&self) -> Vec<String> {
+        let names: Vec<&str> = match self {
+            NameList::Joined(joined) => joined.split(|c: char| c == ',' || c.is_whitespace()).collect(),
+            NameList::List(list) => list.iter().map(|s| s.as_str()).collect(),
+        };
+        
+        names.into_iter()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect()
+    }
+}
+
+/// A list of names query parameters, or the JSON body of the POST variant.
+/// It supports both a single username and a comma‑separated list (or an array in JSON).
+#[derive(Deserialize, IntoParams, ToSchema)]
+#[into_params(parameter_in = Query)]
+struct QueryParams {
+    /// auth token, unless it's sent as `Authorization: Bearer <token>` instead.
+    token: Option<String>,
+    /// if provided, the "usernames" parameter contains a list separated by commas, newlines or spaces.
+    #[param(value_type = Option<String>)]
+    #[schema(value_type = Option<Vec<String>>)]
+    usernames: Option<NameList>,
+    /// alternative single username parameter.
+    username: Option<String>,
+    /// list of numeric user IDs (separated like usernames), resolved to their current usernames.
+    #[param(value_type = Option<String>)]
+    #[schema(value_type = Option<Vec<String>>)]
+    user_ids: Option<NameList>,
+    /// response format, "json", "csv", "rss", "ndjson", "jsonld" or "event-stream". Negotiated from the Accept header when absent.
+    format: Option<String>,
+    /// also fetch the user's current stories (requires SESSION_COOKIE).
+    include_stories: Option<bool>,
+    /// also return the user's highlight titles and covers.
+    include_highlights: Option<bool>,
+    /// also return posts the user is tagged in (one extra upstream request per user).
+    include_tagged: Option<bool>,
+    /// also return the user's reels (one extra upstream request per user unless the profile embeds them).
+    include_reels: Option<bool>,
+    /// pagination cursor, the end_cursor of a previous response.
+    after: Option<String>,
+    /// re-fetch cached users older than this many seconds, for fresher data than the cache TTL gives.
+    max_age_seconds: Option<u64>,
+    /// only return posts from the last this many days, applied before the limit.
+    max_age_days: Option<u32>,
+    /// post node fields to copy verbatim into each post's "extra" map, as dot-separated paths.
+    #[param(value_type = Option<String>)]
+    #[schema(value_type = Option<Vec<String>>)]
+    extra_post_fields: Option<NameList>,
+    /// upstream timeout in milliseconds for this request's fetches (at most 30000), instead of REQUEST_TIMEOUT_SECONDS.
+    timeout_ms: Option<u64>,
+    /// number of posts to return per user (default DEFAULT_POST_LIMIT, at most MAX_POST_LIMIT).
+    limit: Option<usize>,
+    /// only return these top-level fields of each user in JSON responses, e.g. "username,followers_count".
+    #[param(value_type = Option<String>)]
+    #[schema(value_type = Option<Vec<String>>)]
+    fields: Option<NameList>,
+    /// only return posts newer than this one, the last shortcode a client has already seen.
+    since_shortcode: Option<String>,
+    /// wrap JSON responses as {"data": [...], "meta": {...}} instead of the bare array.
+    envelope: Option<bool>,
+    /// indent JSON responses for reading them by hand.
+    pretty: Option<bool>,
+    /// return JSON as one flat list of posts across all users, each with its "username", instead of
+    /// the per-user objects. Profile fields, errors and the fields parameter don't apply.
+    posts_only: Option<bool>,
+    /// return Instagram's own user object for a single username, verbatim and uncached. Needs ALLOW_RAW.
+    raw: Option<bool>,
+}
+
+// JSON response wrapped with request-level metadata, for envelope=true
+#[derive(Serialize)]
+struct Envelope<T: Serialize> {
+    data: T,
+    meta: ResponseMeta,
+}
+
+#[derive(Serialize)]
+struct ResponseMeta {
+    // users served from the cache, including stale copies
+    cached_count: usize,
+    // users fetched from Instagram for this request
+    fetched_count: usize,
+    took_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+// Output formats supported by the posts endpoint
+#[derive(Clone, Copy, PartialEq, Hash)]
+enum ResponseFormat {
+    Json,
+    Csv,
+    Rss,
+    // One JSON object per line, streamed as each user's fetch completes
+    Ndjson,
+    // schema.org ProfilePage documents, for embedding in pages as structured data
+    JsonLd,
+    // Server-sent events, one per user as it completes and a final "done" event
+    EventStream,
+}
+
+impl ResponseFormat {
+    // Format named by the "format" parameter
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "json" => Some(ResponseFormat::Json),
+            "csv" => Some(ResponseFormat::Csv),
+            "rss" => Some(ResponseFormat::Rss),
+            "ndjson" => Some(ResponseFormat::Ndjson),
+            "jsonld" => Some(ResponseFormat::JsonLd),
+            "event-stream" => Some(ResponseFormat::EventStream),
+            _ => None,
+        }
+    }
+    
+    // Format negotiated from the Accept header, JSON when it's absent, */* or lists nothing we support
+    fn from_accept(req: &HttpRequest) -> Self {
+        let Ok(accept) = header::Accept::parse(req) else {
+            return ResponseFormat::Json;
+        };
+        
+        for mime in accept.ranked() {
+            match mime.essence_str() {
+                "application/json" | "*/*" => return ResponseFormat::Json,
+                "text/csv" => return ResponseFormat::Csv,
+                "application/rss+xml" => return ResponseFormat::Rss,
+                "application/x-ndjson" => return ResponseFormat::Ndjson,
+                "application/ld+json" => return ResponseFormat::JsonLd,
+                "text/event-stream" => return ResponseFormat::EventStream,
+                _ => {}
+            }
+        }
+        
+        ResponseFormat::Json
+    }
+}
+
+// One of the configured User-Agents, picked at random
+fn random_user_agent(state: &AppState) -> &str {
+    state.user_agents
+        .choose(&mut rand::rng())
+        .map(|ua| ua.as_str())
+        .unwrap_or(DEFAULT_USER_AGENTS[0])
+}
+
+// Timeout for an upstream request: the request's timeout_ms if it gave one, otherwise REQUEST_TIMEOUT_SECONDS
+fn upstream_timeout(state: &AppState) -> Duration {
+    UPSTREAM_TIMEOUT.try_with(|timeout| *timeout).unwrap_or(state.runtime().request_timeout)
+}
+
+// Build a GET request against Instagram with the headers the web client sends
+fn instagram_request(state: &AppState, url: &str) -> RequestBuilder {
+    // Fresh device/session identifiers per request, so we don't present the same fingerprint every time.
+    // The web client sends the device id as an uppercase hyphenated UUID.
+    let device_id = Uuid::new_v4().hyphenated().to_string().to_uppercase();
+    let session_id = Uuid::new_v4().hyphenated().to_string();
+    
+    // Rotate the User-Agent per request. The App ID below stays fixed since it has to match a real web client.
+    state.client.get(url)
+        .header("User-Agent", random_user_agent(state))
+        .header("Accept", "*/*")
+        .header("Accept-Language", "en-US,en;q=0.5")
+        .header("X-IG-App-ID", "936619743392459") // Instagram App ID
+        .header("X-ASBD-ID", "359341")
+        .header("X-IG-WWW-Claim", state.www_claim.read().unwrap_or_else(PoisonError::into_inner).as_str())
+        .header("X-Web-Device-Id", device_id)
+        .header("X-Web-Session-ID", session_id)
+        .header("X-Requested-With", "XMLHttpRequest")
+        .header("Sec-GPC", "1")
+        // Configured extra headers replace any of the defaults above with the same name
+        .headers(state.extra_headers.clone())
+        .timeout(upstream_timeout(state))
+}
+
+// Pick up what we track from every upstream response's headers, before its body is consumed
+fn track_response(state: &AppState, resp: &reqwest::Response) {
+    track_www_claim(state, resp);
+    track_rate_limit_headers(state, resp);
+}
+
+/// Rate-limit hints Instagram attached to its latest response that had any
+#[derive(Serialize, Clone, ToSchema)]
+struct UpstreamRateLimit {
+    /// The x-ratelimit-* style headers and retry-after, by lowercase name
+    headers: HashMap<String, String>,
+    seen_at: String,
+}
+
+fn is_rate_limit_header(name: &str) -> bool {
+    name == "retry-after" || name.contains("ratelimit") || name.contains("rate-limit")
+}
+
+// Log and remember any rate-limit headers, as an early warning before requests start getting blocked
+fn track_rate_limit_headers(state: &AppState, resp: &reqwest::Response) {
+    let headers: HashMap<String, String> = resp.headers().iter()
+        .filter(|(name, _)| is_rate_limit_header(name.as_str()))
+        .filter_map(|(name, value)| Some((name.as_str().to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    if headers.is_empty() {
+        return;
+    }
+    
+    let mut pairs: Vec<String> = headers.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+    pairs.sort();
+    info!("Upstream rate-limit headers from {}: {}", resp.url().path(), pairs.join(", "));
+    
+    *state.upstream_rate_limit.write().unwrap_or_else(PoisonError::into_inner) = Some(UpstreamRateLimit {
+        headers,
+        seen_at: Utc::now().to_rfc3339(),
+    });
+}
+
+// Full upstream URL for a path on INSTAGRAM_BASE_URL, which tests can point at a fixture server
+fn instagram_url(state: &AppState, path: &str) -> String {
+    format!("{}{}", state.instagram_base_url, path)
+}
+
+// Remember the rotated claim Instagram sends in x-ig-set-www-claim, for use on the following requests
+fn track_www_claim(state: &AppState, resp: &reqwest::Response) {
+    let Some(claim) = resp.headers().get("x-ig-set-www-claim").and_then(|v| v.to_str().ok()) else {
+        return;
+    };
+    if claim.is_empty() {
+        return;
+    }
+    
+    let mut current = state.www_claim.write().unwrap_or_else(PoisonError::into_inner);
+    if *current != claim {
+        debug!("Instagram rotated the WWW claim");
+        *current = claim.to_string();
+    }
+}
+
+// Read an upstream body, giving up once it exceeds max_response_bytes so a misbehaving
+// upstream can't stream us out of memory. The Content-Length check catches most cases up front.
+async fn read_body(state: &AppState, mut resp: reqwest::Response) -> Result<String, FetchError> {
+    if resp.content_length().is_some_and(|len| len > state.max_response_bytes as u64) {
+        warn!("Upstream response from {} declares {} bytes, over the limit", resp.url(), resp.content_length().unwrap_or(0));
+        return Err(FetchError::ResponseTooLarge);
+    }
+    
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        if body.len() + chunk.len() > state.max_response_bytes {
+            warn!("Upstream response from {} exceeded {} bytes", resp.url(), state.max_response_bytes);
+            return Err(FetchError::ResponseTooLarge);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+// Read and parse an upstream JSON body, with the same size limit as read_body
+async fn read_json(state: &AppState, resp: reqwest::Response) -> Result<serde_json::Value, FetchError> {
+    let body = read_body(state, resp).await?;
+    parse_json_body(&body)
+}
+
+// Parse an upstream body as JSON. A login wall or challenge page in its place means we're blocked.
+fn parse_json_body(body: &str) -> Result<serde_json::Value, FetchError> {
+    serde_json::from_str(body).map_err(|_| if is_blocked_page(body) { FetchError::Blocked } else { FetchError::Parse })
+}
+
+// Every request to Instagram goes through here: it waits for an upstream slot at the current fetch's
+// priority, is refused while the circuit is open, and has its outcome fed back into the breaker
+async fn upstream_call<T>(state: &AppState, request: impl std::future::Future<Output = Result<T, FetchError>>) -> Result<T, FetchError> {
+    let priority = UPSTREAM_PRIORITY.try_with(|priority| *priority).unwrap_or(Priority::High);
+    let _slot = state.upstream_queue.acquire(priority).await;
+    
+    if !state.circuit_breaker.allow_request() {
+        info!("Circuit open, not sending the upstream request");
+        return Err(FetchError::Blocked);
+    }
+    
+    let result = request.await;
+    state.circuit_breaker.record(&result);
+    result
+}
+
+// Send a request to Instagram and parse its JSON body, run inside upstream_call.
+// A 404 means whatever was asked for doesn't exist.
+async fn send_json(state: &AppState, request: RequestBuilder) -> Result<serde_json::Value, FetchError> {
+    let resp = request.send().await?;
+    track_response(state, &resp);
+    
+    let status = resp.status();
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Err(FetchError::NotFound);
+    }
+    if !status.is_success() {
+        return Err(status_error(&resp));
+    }
+    
+    read_json(state, resp).await
+}
+
+// Resolve a numeric user ID to the account's current username.
+// IDs are stable across renames, so this lets callers track accounts by ID.
+async fn resolve_username(state: &AppState, user_id: &str) -> Result<String, FetchError> {
+    if user_id.is_empty() || !user_id.chars().all(|c| c.is_ascii_digit()) {
+        info!("Ignoring invalid user ID: {}", user_id);
+        return Err(FetchError::NotFound);
+    }
+    
+    let url = instagram_url(state, &format!("/api/v1/users/{}/info/", user_id));
+    
+    info!("Resolving username for user ID: {}", user_id);
+    
+    let data = upstream_call(state, send_json(state, instagram_request(state, &url))).await?;
+    
+    data.get("user")
+        .and_then(|u| u.get("username"))
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+        .ok_or(FetchError::NotFound)
+}
+
+// Extract a post from a timeline media node (data.user.edge_owner_to_timeline_media.edges[].node)
+fn parse_post_node(node: &serde_json::Value) -> InstagramPost {
+    // Extract image URL, preferring the largest of the display_resources variants over display_url
+    let image_url = largest_display_resource(node)
+        .or_else(|| node.get("display_url").and_then(|v| v.as_str()))
+        .unwrap_or("")
+        .to_string();
+    
+    // Video posts get their poster frame as the preview, alongside the video itself
+    let is_video = node.get("is_video").and_then(|v| v.as_bool()).unwrap_or(false);
+    let video_preview_url = is_video.then(|| image_url.clone());
+    let video_url = node.get("video_url")
+        .and_then(|v| v.as_str())
+        .filter(|url| is_video && !url.is_empty())
+        .map(|url| url.to_string());
+    
+    // Extract shortcode for direct link
+    let shortcode = node.get("shortcode")
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .to_string();
+    
+    let direct_link = format!("https://www.instagram.com/p/{}/", shortcode);
+    
+    // Extract timestamp
+    let timestamp = node.get("taken_at_timestamp")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+    
+    let date = if timestamp > 0 {
+        DateTime::<Utc>::from_timestamp(timestamp, 0)
+            .map(|dt| dt.to_string())
+            .unwrap_or_else(|| String::from("Unknown date"))
+    } else {
+        String::from("Unknown date")
+    };
+    
+    // Like counts live under edge_liked_by or edge_media_preview_like depending on the response
+    let likes_count = node.get("edge_liked_by")
+        .or_else(|| node.get("edge_media_preview_like"))
+        .and_then(|e| e.get("count"))
+        .and_then(|c| c.as_i64())
+        .unwrap_or(0);
+    
+    // Single-post responses count comments under edge_media_to_parent_comment instead
+    let comments_count = node.get("edge_media_to_comment")
+        .or_else(|| node.get("edge_media_to_parent_comment"))
+        .or_else(|| node.get("edge_media_preview_comment"))
+        .and_then(|e| e.get("count"))
+        .and_then(|c| c.as_i64())
+        .unwrap_or(0);
+    
+    // The caption is the first edge of edge_media_to_caption
+    let caption = node.get("edge_media_to_caption")
+        .and_then(|e| e.get("edges"))
+        .and_then(|e| e.get(0))
+        .and_then(|e| e.get("node"))
+        .and_then(|n| n.get("text"))
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .to_string();
+    
+    // Carousels are GraphSidecar nodes, everything else is an image or a video
+    let media_type = match node.get("__typename").and_then(|v| v.as_str()) {
+        Some("GraphSidecar") | Some("XDTGraphSidecar") => "carousel",
+        _ if video_preview_url.is_some() => "video",
+        _ => "image",
+    }.to_string();
+    
+    // View counts and durations only make sense for videos
+    let (video_view_count, video_duration) = if media_type == "video" {
+        (
+            node.get("video_view_count").and_then(|v| v.as_i64()),
+            node.get("video_duration").and_then(|v| v.as_f64()),
+        )
+    } else {
+        (None, None)
+    };
+    
+    let alt_text = node.get("accessibility_caption")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+    
+    let dimensions = node.get("dimensions");
+    let width = dimensions.and_then(|d| d.get("width")).and_then(|v| v.as_i64());
+    let height = dimensions.and_then(|d| d.get("height")).and_then(|v| v.as_i64());
+    
+    // Only single-post lookups include the owner, timeline nodes belong to the profile being fetched
+    let owner_username = node.get("owner")
+        .and_then(|o| o.get("username"))
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+    
+    InstagramPost {
+        image_url,
+        video_preview_url,
+        video_url,
+        shortcode,
+        direct_link,
+        date,
+        timestamp,
+        media_type,
+        likes_count,
+        comments_count,
+        caption,
+        alt_text,
+        width,
+        height,
+        video_view_count,
+        video_duration,
+        owner_username,
+        original_image_url: None,
+        original_video_preview_url: None,
+        extra: HashMap::new(),
+    }
+}
+
+// The src of the highest-resolution variant in display_resources, by config_width x config_height
+fn largest_display_resource(node: &serde_json::Value) -> Option<&str> {
+    node.get("display_resources")?
+        .as_array()?
+        .iter()
+        .filter_map(|resource| {
+            let src = resource.get("src")?.as_str().filter(|src| !src.is_empty())?;
+            let width = resource.get("config_width").and_then(|v| v.as_i64()).unwrap_or(0);
+            let height = resource.get("config_height").and_then(|v| v.as_i64()).unwrap_or(0);
+            Some((width * height, src))
+        })
+        .max_by_key(|(pixels, _)| *pixels)
+        .map(|(_, src)| src)
+}
+
+// Copy the given dot-separated paths (e.g. "clips_music_attribution_info" or "dimensions.height") out of
+// a post node verbatim, for fields we don't extract ourselves. Paths that aren't there are left out.
+fn extract_extra_fields(node: &serde_json::Value, paths: &[String]) -> HashMap<String, serde_json::Value> {
+    paths.iter()
+        .filter_map(|path| {
+            let value = path.split('.').try_fold(node, |value, segment| match value {
+                serde_json::Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+                _ => value.get(segment),
+            })?;
+            Some((path.clone(), value.clone()))
+        })
+        .collect()
+}
+
+// GraphQL query hash for looking up a single post by shortcode
+const SHORTCODE_QUERY_HASH: &str = "b3055c01b4b222b8a47dc12b090e4e64";
+
+// Shortcodes are URL-safe base64, so only letters, digits, '-' and '_'
+fn is_valid_shortcode(shortcode: &str) -> bool {
+    (1..=64).contains(&shortcode.len())
+        && shortcode.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// Fetch a single post by its shortcode via the GraphQL shortcode_media query
+async fn fetch_instagram_post(state: &AppState, shortcode: &str) -> Result<InstagramPost, FetchError> {
+    let mut result = fetch_post_node(state, shortcode).await;
+    
+    if let (Ok(post), Some(proxy)) = (&mut result, ImageProxy::for_state(state)) {
+        proxy.rewrite_post(post);
+    }
+    
+    result
+}
+
+async fn fetch_post_node(state: &AppState, shortcode: &str) -> Result<InstagramPost, FetchError> {
+    let variables = serde_json::json!({ "shortcode": shortcode });
+    
+    info!("Fetching Instagram post: {}", shortcode);
+    
+    let data = graphql_query(state, SHORTCODE_QUERY_HASH, &variables, None).await?;
+    
+    // The structure follows: data.shortcode_media, which is null for deleted or unknown posts
+    let node = data.get("shortcode_media")
+        .filter(|n| !n.is_null())
+        .ok_or(FetchError::NotFound)?;
+    
+    Ok(parse_post_node(node))
+}
+
+// GraphQL query hash for the paginated user timeline
+const TIMELINE_QUERY_HASH: &str = "69cba40317214236af40e7efa697781d";
+
+// One page of a user's timeline
+#[derive(Default)]
+struct TimelinePage {
+    posts: Vec<InstagramPost>,
+    count: i64,
+    end_cursor: Option<String>,
+    has_next_page: bool,
+}
+
+// Extract a page of posts from an edge_owner_to_timeline_media object, copying the extra_fields paths
+// of each node into its extra map
+fn parse_timeline_media(media: &serde_json::Value, limit: usize, extra_fields: &[String]) -> TimelinePage {
+    // Get total posts count
+    let count = media.get("count")
+        .and_then(|c| c.as_i64())
+        .unwrap_or(0);
+    
+    let posts = media.get("edges")
+        .and_then(|e| e.as_array())
+        .map(|edges| edges.iter()
+            .take(limit)
+            .filter_map(|edge| edge.get("node"))
+            .map(|node| InstagramPost {
+                extra: extract_extra_fields(node, extra_fields),
+                ..parse_post_node(node)
+            })
+            .collect())
+        .unwrap_or_default();
+    
+    let page_info = media.get("page_info");
+    
+    let end_cursor = page_info
+        .and_then(|p| p.get("end_cursor"))
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+    
+    let has_next_page = page_info
+        .and_then(|p| p.get("has_next_page"))
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    TimelinePage {
+        posts,
+        count,
+        end_cursor,
+        has_next_page,
+    }
+}
+
+// Run a GraphQL query against Instagram and return its data object, sending the session cookie
+// for queries that need one. A 200 without a data object is a soft block.
+async fn graphql_query(state: &AppState, query_hash: &str, variables: &serde_json::Value, cookie: Option<&str>) -> Result<serde_json::Value, FetchError> {
+    let mut request = instagram_request(state, &instagram_url(state, "/graphql/query/"))
+        .query(&[("query_hash", query_hash), ("variables", &variables.to_string())]);
+    if let Some(cookie) = cookie {
+        request = request.header("Cookie", cookie);
+    }
+    
+    upstream_call(state, async {
+        send_json(state, request).await?
+            .get_mut("data")
+            .map(serde_json::Value::take)
+            .ok_or(FetchError::Blocked)
+    })
+    .await
+}
+
+// Fetch a timeline-shaped edge (edges[].node plus page_info) of the object at data.<root> via a
+// GraphQL query, e.g. data.user.edge_user_to_photos_of_you. A null root means the user, hashtag
+// or location doesn't exist.
+async fn fetch_graphql_edge(
+    state: &AppState,
+    query_hash: &str,
+    variables: serde_json::Value,
+    root: &str,
+    edge: &str,
+    limit: usize,
+    extra_fields: &[String],
+) -> Result<TimelinePage, FetchError> {
+    let data = graphql_query(state, query_hash, &variables, None).await?;
+    
+    data.get(root)
+        .filter(|r| !r.is_null())
+        .ok_or(FetchError::NotFound)?
+        .get(edge)
+        .map(|media| parse_timeline_media(media, limit, extra_fields))
+        .ok_or(FetchError::Parse)
+}
+
+// Fetch the page of a user's timeline following the given cursor via the GraphQL query
+async fn fetch_timeline_page(state: &AppState, user_id: &str, after: &str, limit: usize, extra_fields: &[String]) -> Result<TimelinePage, FetchError> {
+    let variables = serde_json::json!({
+        "id": user_id,
+        "first": limit,
+        "after": after,
+    });
+    
+    info!("Fetching timeline page for user ID {} after cursor {}", user_id, after);
+    
+    // The whole page is kept so the caller can filter by age before applying the limit
+    fetch_graphql_edge(state, TIMELINE_QUERY_HASH, variables, "user", "edge_owner_to_timeline_media", usize::MAX, extra_fields).await
+}
+
+// GraphQL query hash for the posts a user is tagged in
+const TAGGED_QUERY_HASH: &str = "be13233562af2d229b008d2976b998b5";
+
+// Fetch the most recent posts a user is tagged in via the GraphQL query
+async fn fetch_tagged_posts(state: &AppState, user_id: &str, limit: usize, extra_fields: &[String]) -> Result<Vec<InstagramPost>, FetchError> {
+    let variables = serde_json::json!({
+        "id": user_id,
+        "first": limit,
+    });
+    
+    info!("Fetching tagged posts for user ID: {}", user_id);
+    
+    // The structure follows: data.user.edge_user_to_photos_of_you, shaped like the timeline
+    let page = fetch_graphql_edge(state, TAGGED_QUERY_HASH, variables, "user", "edge_user_to_photos_of_you", limit, extra_fields).await?;
+    Ok(page.posts)
+}
+
+// GraphQL query hash for a user's reels (the "felix" video timeline)
+const REELS_QUERY_HASH: &str = "bc78b344a68ed16dd5d7f264681c4c76";
+
+// Fetch a user's most recent reels via the GraphQL query
+async fn fetch_reels(state: &AppState, user_id: &str, limit: usize, extra_fields: &[String]) -> Result<Vec<InstagramPost>, FetchError> {
+    let variables = serde_json::json!({
+        "id": user_id,
+        "first": limit,
+    });
+    
+    info!("Fetching reels for user ID: {}", user_id);
+    
+    // The structure follows: data.user.edge_felix_video_timeline, shaped like the timeline
+    let page = fetch_graphql_edge(state, REELS_QUERY_HASH, variables, "user", "edge_felix_video_timeline", limit, extra_fields).await?;
+    Ok(page.posts)
+}
+
+// GraphQL query hashes for the recent media of a hashtag and of a location
+const HASHTAG_QUERY_HASH: &str = "9b498c08113f1e09617a1703c22b2f32";
+const LOCATION_QUERY_HASH: &str = "1b84447a4d8b6d6d0426fefb34514485";
+
+// Which feed to fetch: a hashtag's or a location's recent posts
+enum MediaFeed<'a> {
+    Hashtag(&'a str),
+    Location(&'a str),
+}
+
+// Fetch the most recent posts of a hashtag or location via the GraphQL query
+async fn fetch_media_feed(state: &AppState, feed: MediaFeed<'_>, limit: usize) -> Result<Vec<InstagramPost>, FetchError> {
+    // The structure follows: data.hashtag.edge_hashtag_to_media or data.location.edge_location_to_media,
+    // both shaped like the timeline and null for unknown tags and locations
+    let (query_hash, variables, root, edge) = match feed {
+        MediaFeed::Hashtag(tag) => {
+            info!("Fetching Instagram hashtag feed: #{}", tag);
+            (HASHTAG_QUERY_HASH, serde_json::json!({ "tag_name": tag, "first": limit }), "hashtag", "edge_hashtag_to_media")
+        }
+        MediaFeed::Location(id) => {
+            info!("Fetching Instagram location feed: {}", id);
+            (LOCATION_QUERY_HASH, serde_json::json!({ "id": id, "first": limit }), "location", "edge_location_to_media")
+        }
+    };
+    
+    let mut result = fetch_graphql_edge(state, query_hash, variables, root, edge, limit, &[]).await
+        .map(|page| page.posts);
+    
+    if let (Ok(posts), Some(proxy)) = (&mut result, ImageProxy::for_state(state)) {
+        posts.iter_mut().for_each(|post| proxy.rewrite_post(post));
+    }
+    
+    result
+}
+
+// GraphQL query hashes for the accounts following a user, and the accounts it follows
+const FOLLOWERS_QUERY_HASH: &str = "c76146de99bb02f6415203be841dd25a";
+const FOLLOWING_QUERY_HASH: &str = "d04b0a864b4b54837c0d870b0e77e076";
+
+// Most accounts Instagram returns per page of a follow list
+const FOLLOW_PAGE_SIZE: usize = 50;
+
+// Which side of a user's follow graph to list
+#[derive(Clone, Copy)]
+enum FollowEdge {
+    Followers,
+    Following,
+}
+
+/// An account in a followers or following list
+#[derive(Serialize, ToSchema)]
+struct FollowUser {
+    username: String,
+    full_name: String,
+    profile_pic_url: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct FollowList {
+    username: String,
+    /// Total followers (or followed accounts), as Instagram counts them
+    count: i64,
+    users: Vec<FollowUser>,
+    /// Cursor for the next page, pass it back as "after" to continue
+    end_cursor: Option<String>,
+    has_next_page: bool,
+}
+
+// A user's numeric ID, from the cached profile when there is one
+async fn lookup_user_id(state: &AppState, username: &str) -> Result<String, FetchError> {
+    let cached = state.cache.get(&CacheKey::new(username, &FetchOptions::new(state)))
+        .and_then(|entry| entry.data.as_ref().ok().map(|data| data.user_id.clone()))
+        .filter(|id| !id.is_empty());
+    if let Some(user_id) = cached {
+        return Ok(user_id);
+    }
+    
+    fetch_profile_api(state, username).await?
+        .get("id")
+        .and_then(|v| v.as_str())
+        .map(|id| id.to_string())
+        .ok_or(FetchError::Parse)
+}
+
+// Walk a user's followers or following from the after cursor, a page at a time, until limit accounts
+// are collected or the list ends. Instagram only serves these to a logged-in session.
+async fn fetch_follow_list(
+    state: &AppState,
+    cookie: &str,
+    username: &str,
+    edge: FollowEdge,
+    after: Option<String>,
+    limit: usize,
+) -> Result<FollowList, FetchError> {
+    let user_id = lookup_user_id(state, username).await?;
+    
+    // The structure follows: data.user.edge_followed_by or data.user.edge_follow, with the
+    // accounts in edges[].node and the paging in page_info
+    let (query_hash, edge_name) = match edge {
+        FollowEdge::Followers => (FOLLOWERS_QUERY_HASH, "edge_followed_by"),
+        FollowEdge::Following => (FOLLOWING_QUERY_HASH, "edge_follow"),
+    };
+    
+    info!("Fetching {} for user: {}", edge_name, username);
+    
+    let mut list = FollowList {
+        username: username.to_string(),
+        count: 0,
+        users: Vec::new(),
+        end_cursor: after,
+        has_next_page: true,
+    };
+    
+    while list.has_next_page && list.users.len() < limit {
+        let mut variables = serde_json::json!({
+            "id": user_id,
+            "first": (limit - list.users.len()).min(FOLLOW_PAGE_SIZE),
+            "include_reel": false,
+        });
+        if let Some(after) = &list.end_cursor {
+            variables["after"] = serde_json::Value::String(after.clone());
+        }
+        
+        let data = graphql_query(state, query_hash, &variables, Some(cookie)).await?;
+        
+        let edges = data.get("user")
+            .filter(|u| !u.is_null())
+            .ok_or(FetchError::NotFound)?
+            .get(edge_name)
+            .ok_or(FetchError::Parse)?;
+        
+        list.count = edges.get("count").and_then(|v| v.as_i64()).unwrap_or(0);
+        
+        let page_info = edges.get("page_info");
+        list.has_next_page = page_info
+            .and_then(|p| p.get("has_next_page"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        list.end_cursor = page_info
+            .and_then(|p| p.get("end_cursor"))
+            .and_then(|v| v.as_str())
+            .filter(|_| list.has_next_page)
+            .map(|cursor| cursor.to_string());
+        
+        let nodes: Vec<&serde_json::Value> = edges.get("edges")
+            .and_then(|e| e.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|edge| edge.get("node"))
+            .collect();
+        
+        // An empty page that still claims more would otherwise be asked for again forever
+        if nodes.is_empty() {
+            break;
+        }
+        
+        let node_str = |node: &serde_json::Value, name: &str| node.get(name)
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string();
+        
+        list.users.extend(nodes.into_iter().map(|node| FollowUser {
+            username: node_str(node, "username"),
+            full_name: node_str(node, "full_name"),
+            profile_pic_url: node_str(node, "profile_pic_url"),
+        }));
+    }
+    
+    if let Some(proxy) = ImageProxy::for_state(state) {
+        for user in &mut list.users {
+            proxy.rewrite(&mut user.profile_pic_url);
+        }
+    }
+    
+    Ok(list)
+}
+
+// Markers Instagram puts in login walls and challenge pages served instead of the real payload
+const BLOCK_MARKERS: &[&str] = &[
+    "/accounts/login",
+    "checkpoint_required",
+    "challenge_required",
+    "Please wait a few minutes before you try again",
+];
+
+// Detect a soft block served with a 200: an HTML login wall or challenge page instead of JSON.
+// JSON responses are checked separately, by whether they contain a user object.
+fn is_blocked_page(body: &str) -> bool {
+    BLOCK_MARKERS.iter().any(|marker| body.contains(marker))
+}
+
+// Fetch a user's active stories from the reels media endpoint. This needs a logged-in session.
+async fn fetch_instagram_stories(state: &AppState, user_id: &str) -> Result<Vec<InstagramStory>, FetchError> {
+    let Some(cookie) = &state.session_cookie else {
+        info!("Skipping stories for user ID {}: SESSION_COOKIE not configured", user_id);
+        return Ok(Vec::new());
+    };
+    
+    let url = instagram_url(state, &format!("/api/v1/feed/reels_media/?reel_ids={}", user_id));
+    
+    info!("Fetching Instagram stories for user ID: {}", user_id);
+    
+    let request = instagram_request(state, &url).header("Cookie", cookie);
+    let data = upstream_call(state, send_json(state, request)).await?;
+    
+    // The structure follows: reels_media[].items[]
+    let items = data.get("reels_media")
+        .and_then(|r| r.get(0))
+        .and_then(|r| r.get("items"))
+        .and_then(|i| i.as_array());
+    
+    let mut stories = Vec::new();
+    
+    for item in items.into_iter().flatten() {
+        // media_type 2 is a video, 1 is an image
+        let is_video = item.get("media_type").and_then(|v| v.as_i64()) == Some(2);
+        
+        // The first version/candidate is the highest quality one
+        let media = if is_video {
+            item.get("video_versions").and_then(|v| v.get(0))
+        } else {
+            item.get("image_versions2").and_then(|v| v.get("candidates")).and_then(|v| v.get(0))
+        };
+        
+        let media_url = media
+            .and_then(|v| v.get("url"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string();
+        
+        let expires_at = item.get("expiring_at")
+            .and_then(|v| v.as_i64())
+            .and_then(|ts| DateTime::<Utc>::from_timestamp(ts, 0))
+            .map(|dt| dt.to_rfc3339())
+            .unwrap_or_default();
+        
+        stories.push(InstagramStory {
+            media_url,
+            is_video,
+            expires_at,
+        });
+    }
+    
+    Ok(stories)
+}
+
+// Extract highlights embedded in the profile response (data.user.edge_highlight_reels.edges[].node)
+fn parse_highlight_reels(reels: &serde_json::Value) -> Vec<Highlight> {
+    let edges = reels.get("edges").and_then(|e| e.as_array());
+    
+    edges.into_iter().flatten()
+        .filter_map(|edge| edge.get("node"))
+        .map(|node| {
+            let cover_image_url = node.get("cover_media_cropped_thumbnail")
+                .and_then(|c| c.get("url"))
+                .or_else(|| node.get("cover_media").and_then(|c| c.get("thumbnail_src")))
+                .and_then(|v| v.as_str())
+                .unwrap_or("")
+                .to_string();
+            
+            Highlight {
+                id: node.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string(),
+                title: node.get("title").and_then(|v| v.as_str()).unwrap_or("").to_string(),
+                cover_image_url,
+            }
+        })
+        .collect()
+}
+
+// Fetch a user's highlights from the highlights tray endpoint. Like stories, this needs a logged-in session.
+async fn fetch_instagram_highlights(state: &AppState, user_id: &str) -> Result<Vec<Highlight>, FetchError> {
+    let Some(cookie) = &state.session_cookie else {
+        info!("Skipping highlights tray for user ID {}: SESSION_COOKIE not configured", user_id);
+        return Ok(Vec::new());
+    };
+    
+    let url = instagram_url(state, &format!("/api/v1/highlights/{}/highlights_tray/", user_id));
+    
+    info!("Fetching Instagram highlights for user ID: {}", user_id);
+    
+    let request = instagram_request(state, &url).header("Cookie", cookie);
+    let data = upstream_call(state, send_json(state, request)).await?;
+    
+    // The structure follows: tray[], with ids like "highlight:17890000000000000"
+    let tray = data.get("tray").and_then(|t| t.as_array());
+    
+    let highlights = tray.into_iter().flatten()
+        .map(|item| {
+            let id = item.get("id")
+                .and_then(|v| v.as_str())
+                .map(|id| id.trim_start_matches("highlight:"))
+                .unwrap_or("")
+                .to_string();
+            
+            let cover_image_url = item.get("cover_media")
+                .and_then(|c| c.get("cropped_image_version"))
+                .and_then(|c| c.get("url"))
+                .and_then(|v| v.as_str())
+                .unwrap_or("")
+                .to_string();
+            
+            Highlight {
+                id,
+                title: item.get("title").and_then(|v| v.as_str()).unwrap_or("").to_string(),
+                cover_image_url,
+            }
+        })
+        .collect();
+    
+    Ok(highlights)
+}
+
+// Log the start of an unexpected upstream body at debug level.
+// Full bodies are large and noisy, so they never go to the info level.
+fn log_body_snippet(username: &str, status: reqwest::StatusCode, body: &str) {
+    let snippet: String = body.chars().take(500).collect();
+    debug!("Upstream response for user {} (status {}): {}", username, status, snippet);
+}
+
+// Fetch the raw user object (data.user) from the web_profile_info API
+async fn fetch_profile_api(state: &AppState, username: &str) -> Result<serde_json::Value, FetchError> {
+    // Direct approach to fetch posts without relying on user ID first
+    let url = instagram_url(state, &format!("/api/v1/users/web_profile_info/?username={}", username));
+    
+    info!("Fetching Instagram data for user: {}", username);
+    
+    upstream_call(state, async {
+        let resp = instagram_request(state, &url)
+            .send()
+            .await?;
+        track_response(state, &resp);
+        
+        let status = resp.status();    
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Err(FetchError::NotFound);
+        }
+        if !status.is_success() {
+            let error = status_error(&resp);
+            log_body_snippet(username, status, &read_body(state, resp).await.unwrap_or_default());
+            return Err(error);
+        }
+        
+        // Being bounced to the login page means we've been soft-blocked
+        let redirected_to_login = resp.url().path().starts_with("/accounts/login");
+        
+        // Get the response body as text first for debugging
+        let body_text = read_body(state, resp).await?;
+        
+        // Try to parse the JSON. A login wall or challenge page in its place means we're blocked.
+        let mut data = match parse_json_body(&body_text) {
+            Ok(json) if !redirected_to_login => json,
+            Err(FetchError::Parse) if !redirected_to_login => {
+                warn!("Could not parse Instagram response for user {}", username);
+                log_body_snippet(username, status, &body_text);
+                return Err(FetchError::Parse);
+            }
+            _ => {
+                warn!("Instagram appears to be blocking requests (user: {})", username);
+                log_body_snippet(username, status, &body_text);
+                return Err(FetchError::Blocked);
+            }
+        };
+        
+        if let Some(reason) = restriction_reason(&data) {
+            info!("Instagram reports user {} as restricted: {}", username, reason);
+            return Err(FetchError::Restricted(reason));
+        }
+        
+        // Extract user information
+        let user = data.get_mut("data")
+            .and_then(|d| d.get_mut("user"))
+            .map(serde_json::Value::take)
+            .filter(|u| !u.is_null());
+        
+        // A 200 without a user object is how rate limiting usually shows up, rather than an empty account
+        user.ok_or_else(|| {
+            warn!("Instagram returned no user data for {}, likely blocked", username);
+            log_body_snippet(username, status, &body_text);
+            FetchError::Blocked
+        })
+    })
+    .await
+}
+
+// Why Instagram won't serve a profile, if it says so. Age-gated accounts come back with a user object
+// flagged is_regulated_c18, temporarily unavailable ones with a "fail" status and a message instead.
+// Other fail messages (rate limiting, mostly) are left for the blocked handling.
+fn restriction_reason(data: &serde_json::Value) -> Option<String> {
+    let age_gated = data.get("data")
+        .and_then(|d| d.get("user"))
+        .and_then(|u| u.get("is_regulated_c18"))
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    if age_gated {
+        return Some("age restricted".to_string());
+    }
+    
+    let message = data.get("message")
+        .and_then(|v| v.as_str())
+        .filter(|_| data.get("status").and_then(|v| v.as_str()) == Some("fail"))?;
+    let lowercase = message.to_ascii_lowercase();
+    
+    (lowercase.contains("unavailable") || lowercase.contains("restricted")).then(|| message.to_string())
+}
+
+// Locate biography_with_entities' mentions and hashtags in its raw_text. Instagram only lists which
+// users and tags appear, so each is searched for after the previous one, and only counts where it
+// isn't followed by more of a name (so "@nasa" doesn't match inside "@nasajpl" or "@nasa.jpl", but
+// does at the end of a sentence). Entities that can't be found are left out.
+fn parse_bio_entities(bio: &serde_json::Value) -> Vec<BioEntity> {
+    let Some(raw_text) = bio.get("raw_text").and_then(|v| v.as_str()) else {
+        return Vec::new();
+    };
+    let Some(entities) = bio.get("entities").and_then(|v| v.as_array()) else {
+        return Vec::new();
+    };
+    
+    // ASCII lowercasing keeps byte offsets the same, and usernames are matched case-insensitively
+    let haystack = raw_text.to_ascii_lowercase();
+    let mut cursor = 0;
+    let mut result = Vec::new();
+    
+    for entity in entities {
+        let mention = entity.get("user").and_then(|u| u.get("username")).and_then(|v| v.as_str());
+        let hashtag = entity.get("hashtag").and_then(|h| h.get("name")).and_then(|v| v.as_str());
+        
+        let (kind, needle) = match (mention, hashtag) {
+            (Some(username), _) => ("mention", format!("@{}", username.to_ascii_lowercase())),
+            (None, Some(name)) => ("hashtag", format!("#{}", name.to_ascii_lowercase())),
+            (None, None) => continue,
+        };
+        
+        let is_name_char = |c: char| c.is_alphanumeric() || c == '_';
+        let found = haystack[cursor..]
+            .match_indices(&needle)
+            .map(|(offset, _)| cursor + offset)
+            .find(|&start| {
+                let rest = &haystack[start + needle.len()..];
+                let continues = rest.starts_with(is_name_char)
+                    || (kind == "mention" && rest.strip_prefix('.').is_some_and(|r| r.starts_with(is_name_char)));
+                !continues
+            });
+        
+        let Some(start) = found else { continue };
+        let end = start + needle.len();
+        
+        result.push(BioEntity {
+            kind: kind.to_string(),
+            text: raw_text[start..end].to_string(),
+            start: raw_text[..start].encode_utf16().count(),
+            end: raw_text[..end].encode_utf16().count(),
+        });
+        cursor = end;
+    }
+    
+    result
+}
+
+// Pull the user object out of a profile page's HTML. Older pages carry it in window._sharedData,
+// newer ones somewhere inside one of the inline <script type="application/json"> blocks.
+fn extract_user_from_html(html: &str, username: &str) -> Option<serde_json::Value> {
+    if let Some(start) = html.find("window._sharedData = ") {
+        let rest = &html[start + "window._sharedData = ".len()..];
+        let end = rest.find(";</script>").unwrap_or(rest.len());
+        
+        let user = serde_json::from_str::<serde_json::Value>(&rest[..end]).ok()
+            .and_then(|data| data.get("entry_data")?.get("ProfilePage")?.get(0)?.get("graphql")?.get("user").cloned());
+        if user.is_some() {
+            return user;
+        }
+    }
+    
+    let mut rest = html;
+    while let Some(start) = rest.find("<script type=\"application/json\"") {
+        rest = &rest[start..];
+        let Some(body_start) = rest.find('>') else { break };
+        let Some(body_end) = rest.find("</script>") else { break };
+        
+        if body_start < body_end {
+            if let Ok(json) = serde_json::from_str::<serde_json::Value>(&rest[body_start + 1..body_end]) {
+                if let Some(user) = find_user_object(&json, username) {
+                    return Some(user.clone());
+                }
+            }
+        }
+        
+        rest = &rest[body_end + "</script>".len()..];
+    }
+    
+    None
+}
+
+// Depth-first search for the profile object of the given user, recognised by its follower count
+fn find_user_object<'a>(value: &'a serde_json::Value, username: &str) -> Option<&'a serde_json::Value> {
+    match value {
+        serde_json::Value::Object(map) => {
+            let is_profile = map.get("username").and_then(|v| v.as_str()) == Some(username)
+                && map.contains_key("edge_followed_by");
+            if is_profile {
+                return Some(value);
+            }
+            map.values().find_map(|v| find_user_object(v, username))
+        }
+        serde_json::Value::Array(items) => items.iter().find_map(|v| find_user_object(v, username)),
+        _ => None,
+    }
+}
+
+// Fallback for when the API is blocked or changed: scrape the user object from the profile page
+async fn fetch_profile_html(state: &AppState, username: &str) -> Result<serde_json::Value, FetchError> {
+    let url = instagram_url(state, &format!("/{}/", username));
+    
+    info!("Fetching Instagram profile page for user: {}", username);
+    
+    upstream_call(state, async {
+        let resp = instagram_request(state, &url)
+            .header("Accept", "text/html,application/xhtml+xml")
+            .send()
+            .await?;
+        track_response(state, &resp);
+        
+        let status = resp.status();
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Err(FetchError::NotFound);
+        }
+        if !status.is_success() {
+            return Err(status_error(&resp));
+        }
+        if resp.url().path().starts_with("/accounts/login") {
+            return Err(FetchError::Blocked);
+        }
+        
+        let html = read_body(state, resp).await?;
+        
+        extract_user_from_html(&html, username).ok_or_else(|| {
+            log_body_snippet(username, status, &html);
+            if is_blocked_page(&html) { FetchError::Blocked } else { FetchError::Parse }
+        })
+    })
+    .await
+}
+
+// Fetch a user, borrowing the result from BACKUP_UPSTREAM_URL when Instagram is blocking us.
+// If the backup can't help either, the original error is returned.
+async fn fetch_instagram_posts(state: &AppState, username: &str, options: &FetchOptions) -> Result<InstagramUserPosts, FetchError> {
+    let result = fetch_with_retries(state, username, options).await;
+    
+    let Some(backup) = state.backup_upstream.as_ref().filter(|_| options.allow_backup) else {
+        return result;
+    };
+    
+    match result {
+        Err(e @ (FetchError::Blocked | FetchError::RateLimited(_))) => {
+            warn!("Fetch for user {} failed ({}), trying backup upstream {}", username, e, backup.url);
+            fetch_from_backup(state, backup, username, options).await.map_err(|backup_err| {
+                warn!("Backup upstream failed for user {}: {}", username, backup_err);
+                e
+            })
+        }
+        result => result,
+    }
+}
+
+// First wait before retrying a 429 that didn't say how long to wait, doubled after each attempt
+const RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(1);
+
+// Fetch from Instagram, retrying 429s up to RATE_LIMIT_RETRIES times. An explicit Retry-After is
+// waited out exactly, otherwise the wait backs off exponentially, either way for at most
+// MAX_RETRY_AFTER_SECONDS. Retrying stops once the next wait would run past the request timeout,
+// so a fetch doesn't outlive the client that asked for it.
+async fn fetch_with_retries(state: &AppState, username: &str, options: &FetchOptions) -> Result<InstagramUserPosts, FetchError> {
+    let mut backoff = RATE_LIMIT_BACKOFF;
+    let deadline = Instant::now() + upstream_timeout(state);
+    
+    for attempt in 1..=state.rate_limit_retries {
+        let retry_after = match fetch_queued(state, username, options).await {
+            Err(FetchError::RateLimited(retry_after)) => retry_after,
+            result => return result,
+        };
+        
+        let delay = retry_after.unwrap_or(backoff).min(state.max_retry_after);
+        if Instant::now() + delay >= deadline {
+            info!("Giving up on user {}, waiting to retry would run past the request timeout", username);
+            return Err(FetchError::RateLimited(retry_after));
+        }
+        
+        match retry_after {
+            Some(retry_after) => info!(
+                "Rate limited fetching user {}, honoring Retry-After of {}s (waiting {}s, attempt {}/{})",
+                username,
+                retry_after.as_secs(),
+                delay.as_secs(),
+                attempt,
+                state.rate_limit_retries
+            ),
+            None => {
+                backoff *= 2;
+                info!(
+                    "Rate limited fetching user {}, retrying in {}s (attempt {}/{})",
+                    username,
+                    delay.as_secs(),
+                    attempt,
+                    state.rate_limit_retries
+                );
+            }
+        }
+        
+        actix_web::rt::time::sleep(delay).await;
+    }
+    
+    fetch_queued(state, username, options).await
+}
+
+// One attempt at Instagram, whose requests each wait for an upstream slot at the fetch's priority.
+// Retry waits and the backup upstream hold none.
+async fn fetch_queued(state: &AppState, username: &str, options: &FetchOptions) -> Result<InstagramUserPosts, FetchError> {
+    UPSTREAM_PRIORITY.scope(options.priority, fetch_from_instagram(state, username, options)).await
+}
+
+// Another instance of this service (or a mirror) to fall back on when we're blocked
+struct BackupUpstream {
+    // Base URL, without a trailing slash, from BACKUP_UPSTREAM_URL
+    url: String,
+    // Its auth token, from BACKUP_UPSTREAM_TOKEN
+    token: Option<String>,
+}
+
+// Marks requests forwarded to a backup, which then won't forward them on again. Keeps two
+// instances that back each other up from bouncing a request between them forever.
+const BACKUP_REQUEST_HEADER: &str = "x-reconned-backup-request";
+
+// Fetch a user's already parsed profile from the backup instance, with the same options
+async fn fetch_from_backup(state: &AppState, backup: &BackupUpstream, username: &str, options: &FetchOptions) -> Result<InstagramUserPosts, FetchError> {
+    let mut query = vec![
+        ("username", username.to_string()),
+        ("limit", options.limit.to_string()),
+        ("include_stories", options.include_stories.to_string()),
+        ("include_highlights", options.include_highlights.to_string()),
+        ("include_tagged", options.include_tagged.to_string()),
+        ("include_reels", options.include_reels.to_string()),
+    ];
+    if let Some(after) = &options.after {
+        query.push(("after", after.clone()));
+    }
+    if let Some(max_age_days) = options.max_age_days {
+        query.push(("max_age_days", max_age_days.to_string()));
+    }
+    if !options.extra_post_fields.is_empty() {
+        query.push(("extra_post_fields", options.extra_post_fields.join(",")));
+    }
+    
+    let mut request = state.client.get(format!("{}/api/instagram_posts", backup.url))
+        .query(&query)
+        .header(BACKUP_REQUEST_HEADER, "1")
+        .header("Accept", "application/json")
+        .timeout(upstream_timeout(state));
+    if let Some(token) = &backup.token {
+        request = request.bearer_auth(token);
+    }
+    
+    let resp = request.send().await?;
+    let status = resp.status();
+    
+    // Partial and total failures still come back as a JSON array, with the error on the entry
+    let body = read_body(state, resp).await?;
+    let entry = serde_json::from_str::<Vec<InstagramUserPosts>>(&body)
+        .map_err(|_| FetchError::Status(status.as_u16()))?
+        .into_iter()
+        .next()
+        .ok_or(FetchError::Parse)?;
+    
+    match entry.error {
+        Some(error) if error == FetchError::NotFound.to_string() => Err(FetchError::NotFound),
+        Some(error) if error.starts_with("Account restricted: ") => {
+            Err(FetchError::Restricted(error["Account restricted: ".len()..].to_string()))
+        }
+        Some(error) => Err(FetchError::Request(format!("backup upstream: {}", error))),
+        None => Ok(entry),
+    }
+}
+
+// Fetch a user's profile and posts from Instagram itself
+async fn fetch_from_instagram(state: &AppState, username: &str, options: &FetchOptions) -> Result<InstagramUserPosts, FetchError> {
+    let api_result = fetch_profile_api(state, username).await;
+    
+    // With HTML_FALLBACK, a blocked or unparseable API response gets a second chance via the profile page.
+    // If that fails too, the original API error is reported.
+    let user = match api_result {
+        Err(e @ (FetchError::Blocked | FetchError::Parse)) if state.html_fallback => {
+            warn!("Profile API failed for user {} ({}), falling back to the HTML page", username, e);
+            fetch_profile_html(state, username).await.map_err(|fallback_err| {
+                warn!("HTML fallback failed for user {}: {}", username, fallback_err);
+                e
+            })?
+        }
+        result => result?,
+    };
+    let user_data = Some(&user);
+    
+    // Extract user profile information
+    let user_id = user_data
+        .and_then(|u| u.get("id"))
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .to_string();
+        
+    let full_name = user_data
+        .and_then(|u| u.get("full_name"))
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .to_string();
+        
+    let biography = user_data
+        .and_then(|u| u.get("biography"))
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .to_string();
+        
+    let bio_entities = user_data
+        .and_then(|u| u.get("biography_with_entities"))
+        .map(parse_bio_entities)
+        .unwrap_or_default();
+    
+    // The link in bio, or the first of the newer bio_links when that's all there is
+    let external_url = user_data
+        .and_then(|u| u.get("external_url"))
+        .and_then(|v| v.as_str())
+        .filter(|url| !url.is_empty())
+        .or_else(|| user_data
+            .and_then(|u| u.get("bio_links"))
+            .and_then(|links| links.get(0))
+            .and_then(|link| link.get("url"))
+            .and_then(|v| v.as_str())
+            .filter(|url| !url.is_empty()))
+        .map(|url| url.to_string());
+    
+    let profile_pic_url = user_data
+        .and_then(|u| u.get("profile_pic_url"))
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .to_string();
+        
+    // Fall back to the low-res picture when there's no HD variant
+    let profile_pic_url_hd = user_data
+        .and_then(|u| u.get("profile_pic_url_hd"))
+        .and_then(|v| v.as_str())
+        .filter(|url| !url.is_empty())
+        .unwrap_or(&profile_pic_url)
+        .to_string();
+        
+    let is_private = user_data
+        .and_then(|u| u.get("is_private"))
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+        
+    let is_verified = user_data
+        .and_then(|u| u.get("is_verified"))
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+        
+    let is_joined_recently = user_data
+        .and_then(|u| u.get("is_joined_recently"))
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    // Badges come either as plain names or as objects carrying one
+    let account_badges = user_data
+        .and_then(|u| u.get("account_badges"))
+        .and_then(|v| v.as_array())
+        .map(|badges| badges.iter()
+            .filter_map(|badge| badge.as_str()
+                .or_else(|| badge.get("name").and_then(|v| v.as_str()))
+                .or_else(|| badge.get("badge_type").and_then(|v| v.as_str())))
+            .map(|badge| badge.to_string())
+            .collect())
+        .unwrap_or_default();
+    
+    // Business details, which Instagram sends as null or "" for personal accounts
+    let business_field = |name: &str| user_data
+        .and_then(|u| u.get(name))
+        .and_then(|v| v.as_str())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_string());
+    
+    let category = business_field("category_name");
+    let business_email = business_field("business_email");
+    let business_phone = business_field("business_phone_number");
+    
+    let is_business = user_data
+        .and_then(|u| u.get("is_business_account"))
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    // Get follower and following counts
+    let followers_count = user_data
+        .and_then(|u| u.get("edge_followed_by"))
+        .and_then(|f| f.get("count"))
+        .and_then(|c| c.as_i64())
+        .unwrap_or(0);
+        
+    let following_count = user_data
+        .and_then(|u| u.get("edge_follow"))
+        .and_then(|f| f.get("count"))
+        .and_then(|c| c.as_i64())
+        .unwrap_or(0);
+    
+    // Extract posts from the response based on the actual structure
+    // The structure follows: data.user.edge_owner_to_timeline_media.edges[].node
+    // With max_age_days the whole page is parsed and filtered first, so the limit counts only recent posts
+    let cutoff = options.max_age_days.map(|days| Utc::now().timestamp() - i64::from(days) * 24 * 60 * 60);
+    let page_limit = if cutoff.is_some() { usize::MAX } else { options.limit };
+    
+    let mut timeline = user_data
+        .and_then(|u| u.get("edge_owner_to_timeline_media"))
+        .map(|media| parse_timeline_media(media, page_limit, &options.extra_post_fields))
+        .unwrap_or_default();
+    
+    // Walk to a later page through the paginated GraphQL query when a cursor was given
+    if let Some(after) = &options.after {
+        if !user_id.is_empty() {
+            timeline = fetch_timeline_page(state, &user_id, after, options.limit, &options.extra_post_fields).await?;
+        }
+    }
+    
+    if let Some(cutoff) = cutoff {
+        timeline.posts.retain(|post| post.timestamp >= cutoff);
+    }
+    timeline.posts.truncate(options.limit);
+    
+    // Stories are a separate request, so a failure there shouldn't lose the profile
+    let stories = if options.include_stories && !user_id.is_empty() {
+        fetch_instagram_stories(state, &user_id).await.unwrap_or_else(|e| {
+            warn!("Failed to fetch stories for user {}: {}", username, e);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+    
+    // Tagged posts are only fetched on request, since they double the upstream requests
+    let tagged_posts = if options.include_tagged && !user_id.is_empty() {
+        fetch_tagged_posts(state, &user_id, options.limit, &options.extra_post_fields).await.unwrap_or_else(|e| {
+            warn!("Failed to fetch tagged posts for user {}: {}", username, e);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+    
+    // Reels come with the profile when Instagram embeds them, otherwise from the reels query
+    let reels = if options.include_reels {
+        let embedded = user_data
+            .and_then(|u| u.get("edge_felix_video_timeline"))
+            .map(|media| parse_timeline_media(media, options.limit, &options.extra_post_fields).posts)
+            .unwrap_or_default();
+        
+        if embedded.is_empty() && !user_id.is_empty() {
+            fetch_reels(state, &user_id, options.limit, &options.extra_post_fields).await.unwrap_or_else(|e| {
+                warn!("Failed to fetch reels for user {}: {}", username, e);
+                Vec::new()
+            })
+        } else {
+            embedded
+        }
+    } else {
+        Vec::new()
+    };
+    
+    // Highlights come with the profile when Instagram includes them, otherwise from the highlights tray
+    let highlights = if options.include_highlights {
+        let embedded = user_data
+            .and_then(|u| u.get("edge_highlight_reels"))
+            .map(parse_highlight_reels)
+            .unwrap_or_default();
+        
+        if embedded.is_empty() && !user_id.is_empty() {
+            fetch_instagram_highlights(state, &user_id).await.unwrap_or_else(|e| {
+                warn!("Failed to fetch highlights for user {}: {}", username, e);
+                Vec::new()
+            })
+        } else {
+            embedded
+        }
+    } else {
+        Vec::new()
+    };
+    
+    let mut user_posts = InstagramUserPosts {
+        user_id,
+        username: username.to_string(),
+        full_name,
+        biography,
+        bio_entities,
+        external_url,
+        profile_pic_url,
+        profile_pic_url_hd,
+        original_profile_pic_url: None,
+        original_profile_pic_url_hd: None,
+        is_private,
+        is_verified,
+        is_joined_recently,
+        account_badges,
+        category,
+        is_business,
+        business_email,
+        business_phone,
+        followers_count,
+        following_count,
+        posts_count: timeline.count,
+        posts: timeline.posts,
+        end_cursor: timeline.end_cursor,
+        has_next_page: timeline.has_next_page,
+        stories,
+        highlights,
+        tagged_posts,
+        reels,
+        fetched_at: Utc::now().to_rfc3339(),
+        stale: false,
+        possible_gap: false,
+        error: None,
+    };
+    
+    if let Some(proxy) = ImageProxy::for_state(state) {
+        proxy.rewrite_profile(&mut user_posts);
+    }
+    
+    // Accounts without a picture get DEFAULT_AVATAR_URL, when set, so clients always have something to render
+    if let Some(default_avatar_url) = &state.default_avatar_url {
+        for url in [&mut user_posts.profile_pic_url, &mut user_posts.profile_pic_url_hd] {
+            if url.is_empty() {
+                *url = default_avatar_url.clone();
+            }
+        }
+    }
+    
+    Ok(user_posts)
+}
+
+// Quote a CSV field when it contains delimiters, quotes or line breaks
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Flatten the response into CSV with one row per post
+fn to_csv(users_posts: &[InstagramUserPosts]) -> String {
+    let mut csv = String::from("username,date,direct_link,image_url,likes_count,comments_count,caption\n");
+    
+    for user in users_posts {
+        for post in &user.posts {
+            let row = [
+                csv_field(&user.username),
+                csv_field(&post.date),
+                csv_field(&post.direct_link),
+                csv_field(&post.image_url),
+                post.likes_count.to_string(),
+                post.comments_count.to_string(),
+                csv_field(&post.caption),
+            ];
+            csv.push_str(&row.join(","));
+            csv.push('\n');
+        }
+    }
+    
+    csv
+}
+
+// Escape text for use in XML element content and attributes
+fn xml_escape(value: &str) -> String {
+    value.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// Render the posts as an RSS 2.0 feed with one item per post
+fn to_rss(users_posts: &[InstagramUserPosts]) -> String {
+    let usernames: Vec<&str> = users_posts.iter().map(|u| u.username.as_str()).collect();
+    
+    let mut rss = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<rss version=\"2.0\">\n<channel>\n");
+    rss.push_str(&format!("<title>Instagram posts from {}</title>\n", xml_escape(&usernames.join(", "))));
+    rss.push_str("<link>https://www.instagram.com/</link>\n");
+    rss.push_str("<description>Recent Instagram posts</description>\n");
+    
+    for user in users_posts {
+        for post in &user.posts {
+            let pub_date = DateTime::<Utc>::from_timestamp(post.timestamp, 0)
+                .filter(|_| post.timestamp > 0)
+                .map(|dt| dt.to_rfc2822());
+            
+            rss.push_str("<item>\n");
+            rss.push_str(&format!("<title>{}</title>\n", xml_escape(&format!("New post by {}", user.username))));
+            rss.push_str(&format!("<link>{}</link>\n", xml_escape(&post.direct_link)));
+            rss.push_str(&format!("<guid>{}</guid>\n", xml_escape(&post.direct_link)));
+            rss.push_str(&format!("<description>{}</description>\n", xml_escape(&post.caption)));
+            if let Some(pub_date) = pub_date {
+                rss.push_str(&format!("<pubDate>{}</pubDate>\n", pub_date));
+            }
+            if !post.image_url.is_empty() {
+                rss.push_str(&format!("<enclosure url=\"{}\" type=\"image/jpeg\" length=\"0\"/>\n", xml_escape(&post.image_url)));
+            }
+            rss.push_str("</item>\n");
+        }
+    }
+    
+    rss.push_str("</channel>\n</rss>\n");
+    rss
+}
+
+// Render the profiles as schema.org JSON-LD, one ProfilePage per user in a @graph, ready to drop into a
+// <script type="application/ld+json"> tag. Users that couldn't be fetched are left out.
+fn to_jsonld(users_posts: &[InstagramUserPosts]) -> serde_json::Value {
+    let pages: Vec<serde_json::Value> = users_posts.iter()
+        .filter(|user| user.error.is_none())
+        .map(|user| {
+            let mut person = serde_json::json!({
+                "@type": "Person",
+                "name": if user.full_name.is_empty() { &user.username } else { &user.full_name },
+                "alternateName": format!("@{}", user.username),
+                "identifier": user.user_id,
+                "url": format!("https://www.instagram.com/{}/", user.username),
+                "interactionStatistic": [
+                    {
+                        "@type": "InteractionCounter",
+                        "interactionType": "https://schema.org/FollowAction",
+                        "userInteractionCount": user.followers_count,
+                    },
+                    {
+                        "@type": "InteractionCounter",
+                        "interactionType": "https://schema.org/WriteAction",
+                        "userInteractionCount": user.posts_count,
+                    },
+                ],
+                "agentInteractionStatistic": {
+                    "@type": "InteractionCounter",
+                    "interactionType": "https://schema.org/FollowAction",
+                    "userInteractionCount": user.following_count,
+                },
+            });
+            
+            if let Some(map) = person.as_object_mut() {
+                if !user.biography.is_empty() {
+                    map.insert("description".to_string(), user.biography.clone().into());
+                }
+                if !user.profile_pic_url.is_empty() {
+                    map.insert("image".to_string(), user.profile_pic_url.clone().into());
+                }
+                if let Some(external_url) = &user.external_url {
+                    map.insert("sameAs".to_string(), external_url.clone().into());
+                }
+            }
+            
+            serde_json::json!({
+                "@type": "ProfilePage",
+                "dateModified": user.fetched_at,
+                "mainEntity": person,
+            })
+        })
+        .collect();
+    
+    serde_json::json!({
+        "@context": "https://schema.org",
+        "@graph": pages,
+    })
+}
+
+// Compute an ETag for a response from the serialized entries and the output format.
+// Entries are sorted before hashing so the same data yields the same tag regardless of username order.
+fn compute_etag(users_posts: &[InstagramUserPosts], format: ResponseFormat, fields: &[String], envelope: bool, pretty: bool, posts_only: bool) -> String {
+    let mut serialized: Vec<String> = users_posts.iter()
+        .map(|entry| serde_json::to_string(entry).unwrap_or_default())
+        .collect();
+    serialized.sort();
+    
+    let mut hasher = DefaultHasher::new();
+    serialized.hash(&mut hasher);
+    format.hash(&mut hasher);
+    fields.hash(&mut hasher);
+    envelope.hash(&mut hasher);
+    pretty.hash(&mut hasher);
+    posts_only.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+// A post along with whose it is, for posts_only responses
+#[derive(Serialize)]
+struct UserPost<'a> {
+    username: &'a str,
+    #[serde(flatten)]
+    post: &'a InstagramPost,
+}
+
+// Every user's posts as one list, in the order the users were requested
+fn flatten_posts(users_posts: &[InstagramUserPosts]) -> Vec<UserPost<'_>> {
+    users_posts.iter()
+        .flat_map(|entry| entry.posts.iter().map(|post| UserPost { username: &entry.username, post }))
+        .collect()
+}
+
+// Project each user down to the requested top-level fields. Unknown names are ignored, and
+// "error" is always kept so failed entries can still be told apart.
+fn project_fields(users_posts: &[InstagramUserPosts], fields: &[String]) -> Vec<serde_json::Value> {
+    users_posts.iter()
+        .map(|entry| project_entry(entry, fields))
+        .collect()
+}
+
+fn project_entry(entry: &InstagramUserPosts, fields: &[String]) -> serde_json::Value {
+    let mut value = serde_json::to_value(entry).unwrap_or_default();
+    if let Some(map) = value.as_object_mut() {
+        map.retain(|key, _| key == "error" || fields.iter().any(|field| field == key));
+    }
+    value
+}
+
+// One NDJSON line for a user, projected like the JSON response when fields were requested
+fn ndjson_line(entry: &InstagramUserPosts, fields: &[String]) -> web::Bytes {
+    let mut line = if fields.is_empty() {
+        serde_json::to_vec(entry)
+    } else {
+        serde_json::to_vec(&project_entry(entry, fields))
+    }
+    .unwrap_or_default();
+    
+    line.push(b'\n');
+    web::Bytes::from(line)
+}
+
+// Check whether the request's If-None-Match header matches the given ETag
+fn etag_matches(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == etag || tag == "*"))
+        .unwrap_or(false)
+}
+
+// OpenAPI description of the public API, served at /openapi.json
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        instagram_handler,
+        instagram_post_handler,
+        instagram_stream_handler,
+        shortcode_handler,
+        image_proxy_handler,
+        hashtag_handler,
+        location_handler,
+        followers_handler,
+        following_handler,
+        validate_handler,
+        cache_summary_handler,
+        cache_clear_handler,
+        config_reload_handler,
+        selftest_handler,
+        health_handler
+    ),
+    components(schemas(InstagramUserPosts, BioEntity, InstagramPost, InstagramStory, Highlight, FollowList, FollowUser, CacheSummary, SelfTestResult, HealthStatus, CircuitStatus, UpstreamRateLimit, ApiError, ApiErrorDetail))
+)]
+struct ApiDoc;
+
+async fn openapi_handler() -> impl Responder {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+/// Profiles and recent posts for one or more users
+///
+/// TypeScript return type (see /openapi.json for the full schema):
+/// `export type InstagramApiResponse = InstagramUserPosts[];`
+#[utoipa::path(
+    get,
+    path = "/api/instagram_posts",
+    params(QueryParams),
+    responses(
+        (status = 200, description = "Profiles and posts for the requested users", body = [InstagramUserPosts]),
+        (status = 207, description = "Some users failed, see each entry's error field", body = [InstagramUserPosts]),
+        (status = 502, description = "Every requested user failed", body = [InstagramUserPosts]),
+        (status = 304, description = "Not modified since the ETag in If-None-Match"),
+        (status = 400, description = "No username provided or unsupported format"),
+        (status = 401, description = "Invalid token")
+    )
+)]
+async fn instagram_handler(req: HttpRequest, query: web::Query<QueryParams>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    handle_posts_request(&req, query.into_inner(), state).await
+}
+
+/// The batch as server-sent events for browser dashboards: a "data:" event with each user as its fetch
+/// completes, then an "event: done". EventSource can't set headers, so the token goes in the query string.
+#[utoipa::path(
+    get,
+    path = "/api/instagram_posts/stream",
+    params(QueryParams),
+    responses(
+        (status = 200, description = "A text/event-stream of InstagramUserPosts events, ending with a done event"),
+        (status = 400, description = "No username provided"),
+        (status = 401, description = "Invalid token")
+    )
+)]
+async fn instagram_stream_handler(req: HttpRequest, query: web::Query<QueryParams>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    let mut query = query.into_inner();
+    query.format = Some("event-stream".to_string());
+    handle_posts_request(&req, query, state).await
+}
+
+/// Same as the GET route, but takes the parameters as a JSON body.
+/// This keeps the token and long username lists out of URLs and access logs.
+#[utoipa::path(
+    post,
+    path = "/api/instagram_posts",
+    request_body = QueryParams,
+    responses(
+        (status = 200, description = "Profiles and posts for the requested users", body = [InstagramUserPosts]),
+        (status = 207, description = "Some users failed, see each entry's error field", body = [InstagramUserPosts]),
+        (status = 502, description = "Every requested user failed", body = [InstagramUserPosts]),
+        (status = 304, description = "Not modified since the ETag in If-None-Match"),
+        (status = 400, description = "No username provided or unsupported format"),
+        (status = 401, description = "Invalid token")
+    )
+)]
+async fn instagram_post_handler(req: HttpRequest, body: web::Json<QueryParams>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    handle_posts_request(&req, body.into_inner(), state).await
+}
+
+async fn handle_posts_request(req: &HttpRequest, query: QueryParams, state: web::Data<Arc<AppState>>) -> HttpResponse {
+    // Validate token first, so unauthenticated requests never take an in-flight slot
+    let mut response = if !is_authorized(req, query.token.as_deref()) {
+        error_response(StatusCode::UNAUTHORIZED, "invalid_token", "Invalid token")
+    } else {
+        // Shed load once MAX_INFLIGHT batches are being handled, rather than queueing without bound
+        let Ok(permit) = state.inflight.clone().try_acquire_owned() else {
+            return server_busy();
+        };
+        
+        posts_response(req, query, state, permit).await
+    };
+    
+    // Only complete successes say how long they may be cached, keep errors and partial results out of CDNs
+    if !response.headers().contains_key(header::CACHE_CONTROL) {
+        response.headers_mut().insert(header::CACHE_CONTROL, header::HeaderValue::from_static("no-store"));
+    }
+    
+    response
+}
+
+// Seconds clients are asked to wait after a 503 from the in-flight limit
+const INFLIGHT_RETRY_AFTER_SECONDS: u64 = 1;
+
+// The 503 for a request turned away by the in-flight limit
+fn server_busy() -> HttpResponse {
+    warn!("Too many requests in flight, rejecting with 503");
+    HttpResponse::ServiceUnavailable()
+        .insert_header((header::RETRY_AFTER, INFLIGHT_RETRY_AFTER_SECONDS.to_string()))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(ApiError::new("server_busy", "Server busy, try again shortly"))
+}
+
+// The token has already been checked. The permit is held until the response is built, or for NDJSON
+// until the last fetch is done.
+async fn posts_response(req: &HttpRequest, query: QueryParams, state: web::Data<Arc<AppState>>, permit: OwnedSemaphorePermit) -> HttpResponse {
+    let started = Instant::now();
+    
+    // An explicit format parameter wins over content negotiation
+    let format = match &query.format {
+        Some(name) => match ResponseFormat::from_name(name) {
+            Some(format) => format,
+            None => return error_response(StatusCode::BAD_REQUEST, "unsupported_format", "Unsupported format"),
+        },
+        None => ResponseFormat::from_accept(req),
+    };
+
+    // Determine the list of usernames to query.
+    let mut usernames: Vec<String> = if let Some(usernames_list) = &query.usernames {
+        usernames_list.to_vec()
+    } else if let Some(username) = &query.username {
+        vec![username.clone()]
+    } else if query.user_ids.is_some() {
+        Vec::new()
+    } else {
+        return error_response(StatusCode::BAD_REQUEST, "no_username", "No username provided");
+    };
+    
+    // Canonical lowercase form for the cache lookup, upstream request and returned username
+    for username in usernames.iter_mut() {
+        *username = normalize_username(username);
+    }
+    
+    // Each user is only looked up once however often it's repeated, keeping the first position
+    let mut seen = HashSet::new();
+    usernames.retain(|username| seen.insert(username.clone()));
+    
+    if query.raw.unwrap_or(false) {
+        if query.user_ids.is_some() {
+            return error_response(StatusCode::BAD_REQUEST, "invalid_parameters", "raw takes a username, not user_ids");
+        }
+        return raw_response(&state, &usernames, query.pretty.unwrap_or(false)).await;
+    }
+    
+    let mut user_ids: Vec<String> = query.user_ids.as_ref()
+        .map(|ids| ids.to_vec())
+        .unwrap_or_default();
+    
+    let mut seen_ids = HashSet::new();
+    user_ids.retain(|id| seen_ids.insert(id.clone()));
+    
+    // Guard against huge fan-outs from a single request
+    let max_usernames_per_request = state.runtime().max_usernames_per_request;
+    if usernames.len() + user_ids.len() > max_usernames_per_request {
+        return error_response(StatusCode::BAD_REQUEST, "too_many_usernames", format!(
+            "Too many usernames, at most {} are allowed per request",
+            max_usernames_per_request
+        ));
+    }
+
+    let options = FetchOptions {
+        limit: state.runtime().post_limit(query.limit),
+        include_stories: query.include_stories.unwrap_or(false),
+        include_highlights: query.include_highlights.unwrap_or(false),
+        include_tagged: query.include_tagged.unwrap_or(false),
+        include_reels: query.include_reels.unwrap_or(false),
+        after: query.after.clone().filter(|a| !a.is_empty()),
+        max_age_days: query.max_age_days,
+        extra_post_fields: query.extra_post_fields.as_ref().map(|f| f.to_vec()).unwrap_or_default(),
+        timeout: query.timeout_ms.map(|ms| Duration::from_millis(ms.clamp(1, MAX_TIMEOUT_MS))),
+        allow_backup: !req.headers().contains_key(BACKUP_REQUEST_HEADER),
+        priority: Priority::High,
+    };
+
+    let mut users_posts = Vec::new();
+    
+    // The order users were asked for in, user IDs after usernames. Unresolved IDs are keyed by the ID itself.
+    let mut input_order = usernames.clone();
+    
+    // Resolve user IDs to their current usernames, which then go through the normal cache path
+    if !user_ids.is_empty() {
+        let state_ref = &state;
+        let resolved: Vec<_> = stream::iter(&user_ids)
+            .map(|id| resolve_username(state_ref, id))
+            .buffered(state.runtime().max_concurrency)
+            .collect()
+            .await;
+        
+        for (user_id, username) in user_ids.iter().zip(resolved) {
+            match username {
+                // Skip IDs resolving to a user that's already in the request
+                Ok(username) => {
+                    let username = normalize_username(&username);
+                    if seen.insert(username.clone()) {
+                        input_order.push(username.clone());
+                        usernames.push(username);
+                    }
+                }
+                Err(e) => {
+                    input_order.push(user_id.clone());
+                    users_posts.push(InstagramUserPosts {
+                        user_id: user_id.clone(),
+                        ..InstagramUserPosts::failed("", &format!("Could not resolve user ID: {}", e))
+                    });
+                }
+            }
+        }
+    }
+    
+    let mut usernames_to_fetch = Vec::new();
+    let mut usernames_to_refresh = Vec::new();
+    
+    // Reject invalid usernames up front instead of sending them upstream
+    usernames.retain(|username| {
+        if is_valid_username(username) {
+            true
+        } else {
+            info!("Rejecting invalid username: {}", username);
+            users_posts.push(InstagramUserPosts::failed(username, "Invalid username"));
+            false
+        }
+    });
+    
+    // With ALLOWED_USERNAMES set, anyone else is refused without ever reaching Instagram
+    let mut forbidden = 0;
+    if let Some(allowed) = &state.allowed_usernames {
+        usernames.retain(|username| {
+            if allowed.contains(username) {
+                true
+            } else {
+                info!("Rejecting username not in ALLOWED_USERNAMES: {}", username);
+                users_posts.push(InstagramUserPosts::failed(username, "Username not allowed"));
+                forbidden += 1;
+                false
+            }
+        });
+    }
+    
+    // Check cache for each username
+    {
+        let now = Instant::now();
+        let stale_grace = state.stale_grace();
+        let max_age = query.max_age_seconds.map(Duration::from_secs);
+        
+        // Check for cached entries. Expired entries are swept by run_cache_sweeper, so
+        // only the entries for these usernames are looked at here.
+        for username in &usernames {
+            // Entries older than the request's max_age_seconds count as misses, whatever their TTL
+            let cached = state.cache_enabled.then(|| state.cache.get_mut(&CacheKey::new(username, &options)))
+                .flatten()
+                .filter(|entry| max_age.is_none_or(|max_age| now.duration_since(entry.timestamp) <= max_age));
+            
+            match cached {
+                Some(mut entry) if entry.is_fresh(now) => {
+                    // Cache hit
+                    info!("Cache hit for user: {}", username);
+                    entry.last_accessed = now;
+                    users_posts.push(match &entry.data {
+                        Ok(data) => data.clone(),
+                        Err(e) => InstagramUserPosts {
+                            fetched_at: entry.fetched_at.to_rfc3339(),
+                            ..InstagramUserPosts::failed(username, &e.to_string())
+                        },
+                    });
+                }
+                Some(mut entry) if state.cache_mode == CacheMode::StaleWhileRevalidate
+                    && entry.data.is_ok()
+                    && entry.is_within(now, stale_grace) => {
+                    // Serve the expired copy right away and refresh it in the background
+                    info!("Serving stale cache entry for user: {}", username);
+                    entry.last_accessed = now;
+                    if let Ok(data) = &entry.data {
+                        users_posts.push(InstagramUserPosts {
+                            stale: true,
+                            ..data.clone()
+                        });
+                    }
+                    if !entry.refreshing {
+                        entry.refreshing = true;
+                        usernames_to_refresh.push(username.clone());
+                    }
+                }
+                _ => {
+                    // Cache miss or expired
+                    usernames_to_fetch.push(username.clone());
+                }
+            }
+        }
+    }
+    
+    state.posts_requests.fetch_add(1, Ordering::Relaxed);
+    state.cache_hits.fetch_add((usernames.len() - usernames_to_fetch.len()) as u64, Ordering::Relaxed);
+    state.cache_misses.fetch_add(usernames_to_fetch.len() as u64, Ordering::Relaxed);
+    
+    for username in usernames_to_refresh {
+        spawn_with_request_id(refresh_cache_entry(state.get_ref().clone(), username, options.clone()));
+    }
+    
+    let fields = query.fields.as_ref().map(|f| f.to_vec()).unwrap_or_default();
+    let since_shortcode = query.since_shortcode.clone().filter(|s| !s.is_empty());
+    
+    // NDJSON and event streams don't wait for the whole batch, cached users go out first and the rest as they arrive
+    if matches!(format, ResponseFormat::Ndjson | ResponseFormat::EventStream) {
+        return stream_batch(format, state.get_ref().clone(), users_posts, usernames, usernames_to_fetch, options, fields, since_shortcode, started, permit);
+    }
+    
+    // Fetch data for uncached usernames
+    if !usernames_to_fetch.is_empty() {
+        // Process usernames concurrently, with at most max_concurrency upstream requests in flight.
+        let state_ref = &state;
+        let options_ref = &options;
+        let results: Vec<_> = stream::iter(&usernames_to_fetch)
+            .map(|uname| fetch_and_cache(state_ref, uname, options_ref))
+            .buffer_unordered(state.runtime().max_concurrency)
+            .collect()
+            .await;
+        
+        users_posts.extend(results);
+    }
+    
+    log_if_slow(&state, started, &usernames, usernames_to_fetch.len());
+    
+    // Cache hits and failures were collected first, put everything back in the requested order
+    sort_by_input_order(&mut users_posts, &input_order);
+    
+    if let Some(since_shortcode) = &since_shortcode {
+        for entry in users_posts.iter_mut() {
+            apply_since_shortcode(entry, since_shortcode);
+        }
+    }
+
+    // Surface partial and total failures at the HTTP level
+    let failures = users_posts.iter().filter(|entry| entry.error.is_some()).count();
+    let status = if failures == 0 {
+        StatusCode::OK
+    } else if failures < users_posts.len() {
+        StatusCode::MULTI_STATUS
+    } else if forbidden == users_posts.len() {
+        StatusCode::FORBIDDEN
+    } else {
+        StatusCode::BAD_GATEWAY
+    };
+    
+    let cache_control = if status == StatusCode::OK {
+        cache_control_for(&state, &users_posts, &options)
+    } else {
+        "no-store".to_string()
+    };
+
+    // Let pollers skip re-downloading unchanged data
+    let envelope = query.envelope.unwrap_or(false);
+    let pretty = query.pretty.unwrap_or(false);
+    let posts_only = query.posts_only.unwrap_or(false);
+    let etag = compute_etag(&users_posts, format, &fields, envelope, pretty, posts_only);
+    if etag_matches(req, &etag) {
+        return HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .insert_header((header::CACHE_CONTROL, cache_control))
+            .insert_header((header::VARY, "Accept, Authorization"))
+            .finish();
+    }
+
+    let mut response = HttpResponse::build(status);
+    response
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::CACHE_CONTROL, cache_control))
+        .insert_header((header::VARY, "Accept, Authorization"));
+
+    match format {
+        ResponseFormat::Json if envelope => {
+            let data = if posts_only {
+                serde_json::to_value(flatten_posts(&users_posts)).unwrap_or_default()
+            } else if fields.is_empty() {
+                serde_json::to_value(&users_posts).unwrap_or_default()
+            } else {
+                serde_json::Value::Array(project_fields(&users_posts, &fields))
+            };
+            json_response(&mut response, &Envelope {
+                data,
+                meta: ResponseMeta {
+                    cached_count: usernames.len() - usernames_to_fetch.len(),
+                    fetched_count: usernames_to_fetch.len(),
+                    took_ms: started.elapsed().as_millis(),
+                    request_id: current_request_id(),
+                },
+            }, pretty)
+        }
+        ResponseFormat::Json if posts_only => json_response(&mut response, &flatten_posts(&users_posts), pretty),
+        ResponseFormat::Json if !fields.is_empty() => json_response(&mut response, &project_fields(&users_posts, &fields), pretty),
+        ResponseFormat::Json => json_response(&mut response, &users_posts, pretty),
+        ResponseFormat::Csv => response
+            .content_type("text/csv; charset=utf-8")
+            .body(to_csv(&users_posts)),
+        ResponseFormat::JsonLd => response
+            .content_type("application/ld+json")
+            .body(to_jsonld(&users_posts).to_string()),
+        ResponseFormat::Rss => response
+            .content_type("application/rss+xml; charset=utf-8")
+            .body(to_rss(&users_posts)),
+        ResponseFormat::Ndjson | ResponseFormat::EventStream => unreachable!("streamed formats are handled above"),
+    }
+}
+
+// Finish a response with a JSON body, indented when the request asked for pretty=true
+fn json_response<T: Serialize>(response: &mut HttpResponseBuilder, value: &T, pretty: bool) -> HttpResponse {
+    if !pretty {
+        return response.json(value);
+    }
+    
+    match serde_json::to_string_pretty(value) {
+        Ok(body) => response.content_type("application/json").body(body),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", format!("Failed to serialize response: {}", e)),
+    }
+}
+
+// Instagram's data.user for one account, verbatim, as an escape hatch for fields the typed response
+// doesn't have yet. Off unless ALLOW_RAW is set, since it's large and its shape is whatever Instagram
+// sends today. Nothing is cached, but the fetch still waits its turn and respects the circuit breaker.
+async fn raw_response(state: &AppState, usernames: &[String], pretty: bool) -> HttpResponse {
+    if !state.allow_raw {
+        return error_response(StatusCode::FORBIDDEN, "raw_disabled", "Raw responses are disabled, set ALLOW_RAW to enable them");
+    }
+    
+    let [username] = usernames else {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_parameters", "raw takes exactly one username");
+    };
+    if !is_valid_username(username) {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_username", "Invalid username");
+    }
+    if state.allowed_usernames.as_ref().is_some_and(|allowed| !allowed.contains(username)) {
+        return error_response(StatusCode::FORBIDDEN, "username_not_allowed", "Username not allowed");
+    }
+    
+    match fetch_profile_api(state, username).await {
+        Ok(user) => json_response(&mut HttpResponse::Ok(), &user, pretty),
+        Err(FetchError::NotFound) => error_response(StatusCode::NOT_FOUND, "not_found", "User not found"),
+        Err(e) => error_response(StatusCode::BAD_GATEWAY, "upstream_error", e.to_string()),
+    }
+}
+
+// Cache-Control for a fully successful batch, so the client's cache expires it along with our own:
+// max-age is the time left on the soonest-expiring entry, zero for anything served stale.
+fn cache_control_for(state: &AppState, users_posts: &[InstagramUserPosts], options: &FetchOptions) -> String {
+    let now = Instant::now();
+    
+    let max_age = users_posts.iter()
+        .map(|entry| match state.cache.get(&CacheKey::new(&entry.username, options)) {
+            Some(cached) if !entry.stale => cached.remaining_ttl(now),
+            _ => Duration::ZERO,
+        })
+        .min()
+        .unwrap_or(Duration::ZERO);
+    
+    // Every response needs the token, which may come in the Authorization header rather than the URL,
+    // so only the client's own cache may keep it
+    format!("private, max-age={}", max_age.as_secs())
+}
+
+// Drop the posts from since_shortcode on, keeping only the newer ones above it. When it isn't on the
+// page everything is kept and the entry flagged, since posts between the two may have been missed.
+fn apply_since_shortcode(entry: &mut InstagramUserPosts, since_shortcode: &str) {
+    if entry.error.is_some() {
+        return;
+    }
+    
+    match entry.posts.iter().position(|post| post.shortcode == since_shortcode) {
+        Some(position) => entry.posts.truncate(position),
+        None => entry.possible_gap = true,
+    }
+}
+
+// Sort entries into the order they were requested in. Entries are matched by username,
+// or by user ID for IDs that couldn't be resolved.
+fn sort_by_input_order(users_posts: &mut [InstagramUserPosts], input_order: &[String]) {
+    let mut positions: HashMap<&str, usize> = HashMap::new();
+    for (i, key) in input_order.iter().enumerate() {
+        positions.entry(key.as_str()).or_insert(i);
+    }
+    
+    users_posts.sort_by_key(|entry| {
+        let key = if entry.username.is_empty() { &entry.user_id } else { &entry.username };
+        positions.get(key.as_str()).copied().unwrap_or(usize::MAX)
+    });
+}
+
+// Warn about batches that took longer than SLOW_REQUEST_MS, so the pathological ones stand out
+fn log_if_slow(state: &AppState, started: Instant, usernames: &[String], cache_misses: usize) {
+    let elapsed = started.elapsed();
+    if elapsed < state.runtime().slow_request_threshold {
+        return;
+    }
+    
+    warn!(
+        "Slow request: {}ms for [{}] ({} cache hits, {} misses)",
+        elapsed.as_millis(),
+        usernames.join(", "),
+        usernames.len() - cache_misses,
+        cache_misses
+    );
+}
+
+// Report a failed fetch to Sentry, tagged with the username and request ID. Missing and restricted
+// accounts aren't errors on our side, and nothing is done at all unless SENTRY_DSN is configured.
+fn report_fetch_error(username: &str, error: &FetchError) {
+    if matches!(error, FetchError::NotFound | FetchError::Restricted(_)) || sentry::Hub::current().client().is_none() {
+        return;
+    }
+    
+    sentry::with_scope(
+        |scope| {
+            scope.set_tag("username", username);
+            if let Some(request_id) = current_request_id() {
+                scope.set_tag("request_id", request_id);
+            }
+        },
+        || sentry::capture_message(&format!("Fetch failed: {}", error), sentry::Level::Warning),
+    );
+}
+
+// Takes a finished (or panicked) fetch out of in_flight, so later misses start a new one
+struct InFlightGuard {
+    state: Arc<AppState>,
+    key: FetchKey,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.state.in_flight.remove(&self.key);
+    }
+}
+
+// Fetch a user and cache the result, returning the entry to respond with. Concurrent misses for the
+// same fetch key share a single upstream fetch instead of each firing their own. The fetch runs as its
+// own task, so it still finishes, caches and frees its upstream slot when every request waiting on it
+// has gone away.
+async fn fetch_and_cache(state: &Arc<AppState>, username: &str, options: &FetchOptions) -> InstagramUserPosts {
+    let key = FetchKey::new(username, options);
+    
+    let fetch = match state.in_flight.entry(key.clone()) {
+        Entry::Occupied(in_flight) => {
+            info!("Joining in-flight fetch for user: {}", username);
+            in_flight.get().clone()
+        }
+        Entry::Vacant(slot) => {
+            let state = state.clone();
+            let username = username.to_string();
+            let options = options.clone();
+            
+            let failed_username = username.clone();
+            
+            let task = spawn_with_request_id(async move {
+                let _guard = InFlightGuard { state: state.clone(), key };
+                fetch_uncoalesced(&state, &username, &options).await
+            });
+            
+            let fetch = async move {
+                task.await.unwrap_or_else(|e| {
+                    warn!("Fetch task for user {} failed: {}", failed_username, e);
+                    InstagramUserPosts::failed(&failed_username, "Internal error")
+                })
+            }
+            .boxed()
+            .shared();
+            
+            slot.insert(fetch.clone());
+            fetch
+        }
+    };
+    
+    fetch.await
+}
+
+// Fetch a user from Instagram and cache the result, without joining other requests' fetches
+async fn fetch_uncoalesced(state: &AppState, username: &str, options: &FetchOptions) -> InstagramUserPosts {
+    let res = match options.timeout {
+        Some(timeout) => UPSTREAM_TIMEOUT.scope(timeout, fetch_instagram_posts(state, username, options)).await,
+        None => fetch_instagram_posts(state, username, options).await,
+    };
+    
+    let entry = match &res {
+        Ok(data) => data.clone(),
+        Err(e) => {
+            report_fetch_error(username, e);
+            InstagramUserPosts::failed(username, &e.to_string())
+        }
+    };
+    
+    if let Some(ttl) = state.cache_ttl_for(&res).filter(|_| state.cache_enabled) {
+        cache_insert(&state.cache, CacheKey::new(username, options), CacheEntry::new(res, ttl), state.max_cache_entries);
+    }
+    
+    entry
+}
+
+// Stream a batch as NDJSON or server-sent events: the already available entries right away, then each
+// fetch as it completes. The fetches run in their own task feeding a channel, so they still finish (and
+// get cached) if the client disconnects. Since the status and ETag go out before the results are known,
+// it's always a 200.
+#[allow(clippy::too_many_arguments)]
+fn stream_batch(
+    format: ResponseFormat,
+    state: Arc<AppState>,
+    ready: Vec<InstagramUserPosts>,
+    usernames: Vec<String>,
+    usernames_to_fetch: Vec<String>,
+    options: FetchOptions,
+    fields: Vec<String>,
+    since_shortcode: Option<String>,
+    started: Instant,
+    permit: OwnedSemaphorePermit,
+) -> HttpResponse {
+    let (tx, rx) = futures::channel::mpsc::unbounded();
+    
+    for entry in ready {
+        let _ = tx.unbounded_send(entry);
+    }
+    
+    spawn_with_request_id(async move {
+        let state_ref = &state;
+        let options_ref = &options;
+        let mut results = stream::iter(&usernames_to_fetch)
+            .map(|uname| fetch_and_cache(state_ref, uname, options_ref))
+            .buffer_unordered(state.runtime().max_concurrency);
+        
+        while let Some(entry) = results.next().await {
+            // The receiver is gone once the client disconnects, keep going to fill the cache anyway
+            let _ = tx.unbounded_send(entry);
+        }
+        
+        log_if_slow(&state, started, &usernames, usernames_to_fetch.len());
+        drop(permit);
+    });
+    
+    let event_stream = format == ResponseFormat::EventStream;
+    
+    let body = rx.map(move |mut entry: InstagramUserPosts| {
+        if let Some(since_shortcode) = &since_shortcode {
+            apply_since_shortcode(&mut entry, since_shortcode);
+        }
+        let line = ndjson_line(&entry, &fields);
+        
+        // An SSE frame is the same JSON line behind "data: ", with a blank line ending the event
+        Ok::<_, actix_web::Error>(if event_stream {
+            web::Bytes::from([b"data: ", line.as_ref(), b"\n"].concat())
+        } else {
+            line
+        })
+    });
+    
+    // Event streams end with a "done" event, so EventSource clients know not to reconnect
+    let done = event_stream.then(|| Ok(web::Bytes::from_static(b"event: done\ndata: {}\n\n")));
+    
+    HttpResponse::Ok()
+        .insert_header((header::VARY, "Accept, Authorization"))
+        .content_type(if event_stream { "text/event-stream" } else { "application/x-ndjson" })
+        .streaming(body.chain(stream::iter(done)))
+}
+
+// Re-fetch a stale cache entry in the background. On failure the stale copy stays
+// in place and the next request for it will try again.
+async fn refresh_cache_entry(state: Arc<AppState>, username: String, options: FetchOptions) {
+    info!("Refreshing stale cache entry for user: {}", username);
+    
+    let res = fetch_instagram_posts(&state, &username, &options).await;
+    let key = CacheKey::new(&username, &options);
+    
+    if let Err(e) = &res {
+        report_fetch_error(&username, e);
+    }
+    
+    match state.cache_ttl_for(&res) {
+        Some(ttl) => {
+            cache_insert(&state.cache, key, CacheEntry::new(res, ttl), state.max_cache_entries);
+        }
+        None => {
+            if let Some(mut entry) = state.cache.get_mut(&key) {
+                entry.refreshing = false;
+            }
+        }
+    }
+}
+
+// How often expired entries are swept from the caches
+const CACHE_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Periodically drop expired entries so the request path never has to scan the whole cache.
+// Profiles still servable as stale are kept until their grace period runs out too.
+async fn run_cache_sweeper(state: Arc<AppState>) {
+    let mut ticker = actix_web::rt::time::interval(CACHE_SWEEP_INTERVAL);
+    
+    loop {
+        ticker.tick().await;
+        
+        let now = Instant::now();
+        let stale_grace = state.stale_grace();
+        let before = state.cache.len() + state.post_cache.len() + state.feed_cache.len();
+        
+        state.cache.retain(|_, entry| entry.is_within(now, stale_grace));
+        state.post_cache.retain(|_, entry| entry.is_fresh(now));
+        state.feed_cache.retain(|_, entry| entry.is_fresh(now));
+        
+        let removed = before.saturating_sub(state.cache.len() + state.post_cache.len() + state.feed_cache.len());
+        if removed > 0 {
+            debug!("Swept {} expired cache entries", removed);
+        }
+    }
+}
+
+// Log a one-line summary of cache effectiveness every STATS_INTERVAL_SECONDS, with cumulative counts
+async fn run_stats_logger(state: Arc<AppState>, interval: Duration) {
+    let mut ticker = actix_web::rt::time::interval(interval);
+    // The first tick fires immediately, before there's anything to report
+    ticker.tick().await;
+    
+    loop {
+        ticker.tick().await;
+        
+        let hits = state.cache_hits.load(Ordering::Relaxed);
+        let misses = state.cache_misses.load(Ordering::Relaxed);
+        let lookups = hits + misses;
+        let hit_ratio = if lookups > 0 { hits as f64 * 100.0 / lookups as f64 } else { 0.0 };
+        
+        info!(
+            "Stats: {} requests, {} cache hits, {} misses ({:.1}% hit ratio), {} cached profiles",
+            state.posts_requests.load(Ordering::Relaxed),
+            hits,
+            misses,
+            hit_ratio,
+            state.cache.len()
+        );
+    }
+}
+
+// How often the cache warmer checks for entries that are about to expire
+const WARM_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// Warm entries this long before they expire, so they never actually go cold
+const WARM_REFRESH_LEAD: Duration = Duration::from_secs(5 * 60);
+
+// Upper bound for the warmer's backoff after upstream errors
+const WARM_MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+// Fetch the given users into the cache with their default options, at most max_concurrency at a time.
+// They go through fetch_and_cache like request misses, so a user already being fetched isn't fetched twice.
+// Returns how many fetches failed without anything worth caching.
+async fn warm_cache(state: &Arc<AppState>, usernames: &[&String]) -> usize {
+    info!("Warming cache for {} user(s)", usernames.len());
+    
+    let options = &FetchOptions::background(state);
+    let mut results = stream::iter(usernames)
+        .map(|username| async move {
+            (username, fetch_and_cache(state, username, options).await)
+        })
+        .buffer_unordered(state.runtime().max_concurrency);
+    
+    let mut completed = 0;
+    let mut failures = 0;
+    
+    while let Some((username, entry)) = results.next().await {
+        completed += 1;
+        match &entry.error {
+            None => info!("Warmed cache for user {} ({}/{})", username, completed, usernames.len()),
+            Some(e) => warn!("Cache warm failed for user {} ({}/{}): {}", username, completed, usernames.len(), e),
+        }
+        
+        // Results worth keeping, negative ones included, were cached by the fetch itself
+        let cached = state.cache.get(&CacheKey::new(username, options))
+            .is_some_and(|cached| cached.is_fresh(Instant::now()));
+        if !cached {
+            failures += 1;
+        }
+    }
+    
+    failures
+}
+
+// Keep the configured hot accounts permanently cached by re-fetching them shortly before they expire.
+// Upstream errors double the wait before the next attempt, up to WARM_MAX_BACKOFF.
+async fn run_cache_warmer(state: Arc<AppState>) {
+    let mut delay = WARM_CHECK_INTERVAL;
+    
+    loop {
+        let runtime = state.runtime();
+        let usernames = &runtime.warm_usernames;
+        
+        let due: Vec<&String> = {
+            let now = Instant::now();
+            let options = FetchOptions::new(&state);
+            
+            usernames.iter()
+                .filter(|username| state.cache.get(&CacheKey::new(username, &options))
+                    .map(|entry| match &entry.data {
+                        Ok(data) if !data.is_private => entry.expires_within(now, WARM_REFRESH_LEAD),
+                        // Negative entries (missing, restricted and private accounts) are left alone until
+                        // they expire, their short TTL would otherwise have them re-fetched every round
+                        _ => !entry.is_fresh(now),
+                    })
+                    .unwrap_or(true))
+                .collect()
+        };
+        
+        if !due.is_empty() {
+            let failures = warm_cache(&state, &due).await;
+            
+            delay = if failures > 0 {
+                (delay * 2).min(WARM_MAX_BACKOFF)
+            } else {
+                WARM_CHECK_INTERVAL
+            };
+        }
+        
+        actix_web::rt::time::sleep(delay).await;
+    }
+}
+
+/// Error body shared by all endpoints, {"error": {"code": "...", "message": "..."}}. Clients should
+/// branch on the stable code, the message is for humans and may change.
+#[derive(Serialize, ToSchema)]
+struct ApiError {
+    error: ApiErrorDetail,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ApiErrorDetail {
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn new(code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            error: ApiErrorDetail {
+                code,
+                message: message.into(),
+            },
+        }
+    }
+}
+
+fn error_response(status: StatusCode, code: &'static str, message: impl Into<String>) -> HttpResponse {
+    HttpResponse::build(status).json(ApiError::new(code, message))
+}
+
+// Malformed query strings and bodies get the same error shape instead of actix's plain-text default
+fn invalid_parameters(err: impl Display, _req: &HttpRequest) -> actix_web::Error {
+    let response = error_response(StatusCode::BAD_REQUEST, "invalid_parameters", err.to_string());
+    actix_web::error::InternalError::from_response(err.to_string(), response).into()
+}
+
+/// Query parameters for endpoints that only need the auth token
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct TokenParams {
+    /// auth token, unless it's sent as `Authorization: Bearer <token>` instead.
+    token: Option<String>,
+}
+
+/// Check a token without fetching anything, e.g. as a deploy smoke test
+#[utoipa::path(
+    get,
+    path = "/api/validate",
+    params(TokenParams),
+    responses(
+        (status = 200, description = "The token is valid"),
+        (status = 401, description = "Invalid token")
+    )
+)]
+async fn validate_handler(req: HttpRequest, query: web::Query<TokenParams>) -> impl Responder {
+    if !is_authorized(&req, query.token.as_deref()) {
+        return HttpResponse::Unauthorized().json(serde_json::json!({ "valid": false }));
+    }
+    
+    HttpResponse::Ok().json(serde_json::json!({ "valid": true }))
+}
+
+/// Service health, including whether we're currently backing off from Instagram
+#[derive(Serialize, ToSchema)]
+struct HealthStatus {
+    /// "ok", or "degraded" while the circuit breaker isn't closed
+    status: &'static str,
+    circuit: CircuitStatus,
+    consecutive_failures: u32,
+    /// Seconds until an open circuit lets a probe request through
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cooldown_remaining_seconds: Option<u64>,
+    /// Rate-limit headers from the latest upstream response that carried any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstream_rate_limit: Option<UpstreamRateLimit>,
+    /// Upstream requests waiting for a slot, by priority
+    queued_high: usize,
+    queued_low: usize,
+}
+
+/// Liveness check for load balancers and monitoring, no token required
+#[utoipa::path(
+    get,
+    path = "/health",
+    responses(
+        (status = 200, description = "The service is up", body = HealthStatus)
+    )
+)]
+async fn health_handler(state: web::Data<Arc<AppState>>) -> impl Responder {
+    let circuit = state.circuit_breaker.status();
+    let (queued_high, queued_low) = state.upstream_queue.queued();
+    
+    HttpResponse::Ok().json(HealthStatus {
+        status: if circuit == CircuitStatus::Closed { "ok" } else { "degraded" },
+        circuit,
+        consecutive_failures: state.circuit_breaker.consecutive_failures(),
+        cooldown_remaining_seconds: state.circuit_breaker.cooldown_remaining(),
+        upstream_rate_limit: state.upstream_rate_limit.read().unwrap_or_else(PoisonError::into_inner).clone(),
+        queued_high,
+        queued_low,
+    })
+}
+
+/// Outcome of an end-to-end scrape of the self-test account
+#[derive(Serialize, ToSchema)]
+struct SelfTestResult {
+    ok: bool,
+    username: String,
+    posts_count: usize,
+    took_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Scrape SELFTEST_USERNAME, bypassing the cache, to prove fetching and parsing still work end to end.
+/// Unlike /health this fails when Instagram has changed or blocked us even though the process is up.
+#[utoipa::path(
+    get,
+    path = "/api/selftest",
+    params(TokenParams),
+    responses(
+        (status = 200, description = "Scraping works", body = SelfTestResult),
+        (status = 401, description = "Invalid token"),
+        (status = 503, description = "The self-test account couldn't be scraped", body = SelfTestResult)
+    )
+)]
+async fn selftest_handler(req: HttpRequest, query: web::Query<TokenParams>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    if !is_authorized(&req, query.token.as_deref()) {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid_token", "Invalid token");
+    }
+    
+    let started = Instant::now();
+    let username = &state.selftest_username;
+    
+    // A blocked instance has to fail here, not pass on the backup upstream's data
+    let options = FetchOptions {
+        allow_backup: false,
+        ..FetchOptions::new(&state)
+    };
+    let (posts_count, error) = match fetch_instagram_posts(&state, username, &options).await {
+        Ok(data) if data.user_id.is_empty() => (0, Some("Profile came back without a user ID".to_string())),
+        Ok(data) if data.posts.is_empty() => (0, Some("Profile came back without any posts".to_string())),
+        Ok(data) => (data.posts.len(), None),
+        Err(e) => (0, Some(e.to_string())),
+    };
+    
+    let result = SelfTestResult {
+        ok: error.is_none(),
+        username: username.clone(),
+        posts_count,
+        took_ms: started.elapsed().as_millis(),
+        error,
+    };
+    
+    if result.ok {
+        HttpResponse::Ok().insert_header((header::CACHE_CONTROL, "no-store")).json(result)
+    } else {
+        warn!("Self-test against {} failed: {}", username, result.error.as_deref().unwrap_or_default());
+        HttpResponse::ServiceUnavailable().insert_header((header::CACHE_CONTROL, "no-store")).json(result)
+    }
+}
+
+/// Summary of what's currently cached, for debugging
+#[derive(Serialize, ToSchema)]
+struct CacheSummary {
+    entry_count: usize,
+    post_entry_count: usize,
+    feed_entry_count: usize,
+    /// Rough size of the cached data, based on its serialized JSON
+    approx_memory_bytes: usize,
+    /// Age in seconds of each cached profile, by cache key (the username plus any non-default options)
+    entries: HashMap<String, u64>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/cache",
+    params(TokenParams),
+    responses(
+        (status = 200, description = "Summary of the cache contents", body = CacheSummary),
+        (status = 401, description = "Invalid token")
+    )
+)]
+async fn cache_summary_handler(req: HttpRequest, query: web::Query<TokenParams>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    if !is_authorized(&req, query.token.as_deref()) {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid_token", "Invalid token");
+    }
+    
+    let now = Instant::now();
+    let default_post_limit = state.runtime().default_post_limit;
+    let mut approx_memory_bytes = 0;
+    let mut entries = HashMap::new();
+    
+    for entry in state.cache.iter() {
+        if let Ok(data) = &entry.data {
+            approx_memory_bytes += serde_json::to_vec(data).map(|v| v.len()).unwrap_or(0);
+        }
+        entries.insert(entry.key().describe(default_post_limit), now.duration_since(entry.timestamp).as_secs());
+    }
+    
+    let mut post_entry_count = 0;
+    for entry in state.post_cache.iter() {
+        if let Ok(post) = &entry.data {
+            approx_memory_bytes += serde_json::to_vec(post).map(|v| v.len()).unwrap_or(0);
+        }
+        post_entry_count += 1;
+    }
+    
+    let mut feed_entry_count = 0;
+    for entry in state.feed_cache.iter() {
+        if let Ok(posts) = &entry.data {
+            approx_memory_bytes += serde_json::to_vec(posts).map(|v| v.len()).unwrap_or(0);
+        }
+        feed_entry_count += 1;
+    }
+    
+    HttpResponse::Ok().json(CacheSummary {
+        entry_count: entries.len(),
+        post_entry_count,
+        feed_entry_count,
+        approx_memory_bytes,
+        entries,
+    })
+}
+
+/// Drop everything from the profile, post and feed caches
+#[utoipa::path(
+    delete,
+    path = "/api/cache",
+    params(TokenParams),
+    responses(
+        (status = 200, description = "The caches were cleared"),
+        (status = 401, description = "Invalid token")
+    )
+)]
+async fn cache_clear_handler(req: HttpRequest, query: web::Query<TokenParams>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    if !is_authorized(&req, query.token.as_deref()) {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid_token", "Invalid token");
+    }
+    
+    let cleared = state.cache.len();
+    state.cache.clear();
+    let cleared_posts = state.post_cache.len();
+    state.post_cache.clear();
+    let cleared_feeds = state.feed_cache.len();
+    state.feed_cache.clear();
+    
+    info!("Cleared cache ({} profiles, {} posts, {} feeds)", cleared, cleared_posts, cleared_feeds);
+    
+    HttpResponse::Ok().json(serde_json::json!({
+        "cleared": cleared,
+        "cleared_posts": cleared_posts,
+        "cleared_feeds": cleared_feeds,
+    }))
+}
+
+/// Re-read CONFIG_FILE and swap in the hot-reloadable settings (see RuntimeConfig) without restarting,
+/// which would drop the cache. In-flight batches finish with the settings they started with.
+#[utoipa::path(
+    post,
+    path = "/api/config/reload",
+    params(TokenParams),
+    responses(
+        (status = 200, description = "The settings were reloaded"),
+        (status = 401, description = "Invalid token"),
+        (status = 500, description = "CONFIG_FILE couldn't be read, the previous settings stay in effect")
+    )
+)]
+async fn config_reload_handler(req: HttpRequest, query: web::Query<TokenParams>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    if !is_authorized(&req, query.token.as_deref()) {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid_token", "Invalid token");
+    }
+    
+    if let Err(e) = load_config_file() {
+        warn!("Config reload failed: {}", e);
+        return error_response(StatusCode::INTERNAL_SERVER_ERROR, "config_reload_failed", format!("Failed to read CONFIG_FILE: {}", e));
+    }
+    
+    let runtime = RuntimeConfig::load();
+    info!("Reloaded runtime configuration");
+    runtime.log();
+    
+    let response = serde_json::json!({
+        "cache_ttl_seconds": runtime.cache_ttl.as_secs(),
+        "cache_ttl_jitter_seconds": runtime.cache_ttl_jitter.as_secs(),
+        "negative_cache_ttl_seconds": runtime.negative_cache_ttl.as_secs(),
+        "request_timeout_seconds": runtime.request_timeout.as_secs(),
+        "max_concurrency": runtime.max_concurrency,
+        "max_usernames_per_request": runtime.max_usernames_per_request,
+        "slow_request_ms": runtime.slow_request_threshold.as_millis(),
+        "warm_usernames": runtime.warm_usernames,
+        "default_post_limit": runtime.default_post_limit,
+        "max_post_limit": runtime.max_post_limit,
+    });
+    
+    *state.runtime.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(runtime);
+    
+    HttpResponse::Ok().json(response)
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PostLookupParams {
+    /// auth token, unless it's sent as `Authorization: Bearer <token>` instead.
+    token: Option<String>,
+    shortcode: String,
+}
+
+/// Look up a single post by shortcode, e.g. from a /p/{shortcode}/ link
+#[utoipa::path(
+    get,
+    path = "/api/instagram_post",
+    params(PostLookupParams),
+    responses(
+        (status = 200, description = "The post, including its owner's username", body = InstagramPost),
+        (status = 400, description = "Invalid shortcode"),
+        (status = 401, description = "Invalid token"),
+        (status = 403, description = "The post's owner isn't in ALLOWED_USERNAMES"),
+        (status = 404, description = "Post not found"),
+        (status = 502, description = "Instagram request failed")
+    )
+)]
+async fn shortcode_handler(req: HttpRequest, query: web::Query<PostLookupParams>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    // Validate token
+    if !is_authorized(&req, query.token.as_deref()) {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid_token", "Invalid token");
+    }
+    
+    let shortcode = query.shortcode.trim();
+    if !is_valid_shortcode(shortcode) {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_shortcode", "Invalid shortcode");
+    }
+    
+    // Check the post cache first
+    let cached = {
+        let now = Instant::now();
+        state.cache_enabled.then(|| state.post_cache.get_mut(shortcode))
+            .flatten()
+            .filter(|entry| entry.is_fresh(now))
+            .map(|mut entry| {
+                entry.last_accessed = now;
+                entry.data.clone()
+            })
+    };
+    
+    let result = match cached {
+        Some(result) => {
+            info!("Cache hit for post: {}", shortcode);
+            result
+        }
+        None => {
+            let result = fetch_instagram_post(&state, shortcode).await;
+            
+            // Like profiles, not-found posts are cached with the negative TTL and transient failures aren't cached
+            let ttl = match &result {
+                Ok(_) => Some(state.runtime().cache_ttl),
+                Err(FetchError::NotFound) => Some(state.runtime().negative_cache_ttl),
+                Err(_) => None,
+            };
+            if let Some(ttl) = ttl.filter(|_| state.cache_enabled) {
+                cache_insert(&state.post_cache, shortcode.to_string(), CacheEntry::new(result.clone(), state.jittered(ttl)), state.max_cache_entries);
+            }
+            
+            result
+        }
+    };
+    
+    // With ALLOWED_USERNAMES set, only posts by those accounts are served
+    let owner_allowed = |post: &InstagramPost| match (&state.allowed_usernames, &post.owner_username) {
+        (None, _) => true,
+        (Some(allowed), Some(owner)) => allowed.contains(&normalize_username(owner)),
+        (Some(_), None) => false,
+    };
+    
+    match result {
+        Ok(post) if !owner_allowed(&post) => {
+            error_response(StatusCode::FORBIDDEN, "username_not_allowed", "Post owner not allowed")
+        }
+        Ok(post) => HttpResponse::Ok().json(post),
+        Err(FetchError::NotFound) => error_response(StatusCode::NOT_FOUND, "not_found", "Post not found"),
+        Err(e) => error_response(StatusCode::BAD_GATEWAY, "upstream_error", e.to_string()),
+    }
+}
+
+// How long hashtag and location feeds stay cached. They move much faster than profiles.
+const FEED_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+// Feeds mix posts from any account and their nodes don't say whose they are, so they're turned off
+// when ALLOWED_USERNAMES restricts which accounts may be served
+fn feeds_not_allowed() -> HttpResponse {
+    error_response(StatusCode::FORBIDDEN, "feed_not_allowed", "Feeds are disabled while ALLOWED_USERNAMES is set")
+}
+
+// Hashtags are letters, digits and underscores. A leading "#" is accepted and dropped.
+fn normalize_hashtag(tag: &str) -> Option<String> {
+    let tag = tag.trim().trim_start_matches('#').to_lowercase();
+    let valid = (1..=100).contains(&tag.chars().count())
+        && tag.chars().all(|c| c.is_alphanumeric() || c == '_');
+    valid.then_some(tag)
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct HashtagParams {
+    /// auth token, unless it's sent as `Authorization: Bearer <token>` instead.
+    token: Option<String>,
+    /// the hashtag, with or without the leading "#"
+    tag: String,
+    /// number of posts to return (default DEFAULT_POST_LIMIT, at most MAX_POST_LIMIT)
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct LocationParams {
+    /// auth token, unless it's sent as `Authorization: Bearer <token>` instead.
+    token: Option<String>,
+    /// numeric location ID, as in instagram.com/explore/locations/{location_id}/
+    location_id: String,
+    /// number of posts to return (default DEFAULT_POST_LIMIT, at most MAX_POST_LIMIT)
+    limit: Option<usize>,
+}
+
+/// Recent posts for a hashtag
+#[utoipa::path(
+    get,
+    path = "/api/instagram_hashtag",
+    params(HashtagParams),
+    responses(
+        (status = 200, description = "The hashtag's most recent posts", body = [InstagramPost]),
+        (status = 400, description = "Invalid hashtag"),
+        (status = 401, description = "Invalid token"),
+        (status = 403, description = "Feeds are disabled while ALLOWED_USERNAMES is set"),
+        (status = 404, description = "Hashtag not found"),
+        (status = 502, description = "Instagram request failed")
+    )
+)]
+async fn hashtag_handler(req: HttpRequest, query: web::Query<HashtagParams>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    if !is_authorized(&req, query.token.as_deref()) {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid_token", "Invalid token");
+    }
+    if state.allowed_usernames.is_some() {
+        return feeds_not_allowed();
+    }
+    
+    let Some(tag) = normalize_hashtag(&query.tag) else {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_hashtag", "Invalid hashtag");
+    };
+    let limit = state.runtime().post_limit(query.limit);
+    
+    let key = format!("tag:{}:{}", tag, limit);
+    feed_response(&state, key, fetch_media_feed(&state, MediaFeed::Hashtag(&tag), limit)).await
+}
+
+/// Recent posts tagged with a location
+#[utoipa::path(
+    get,
+    path = "/api/instagram_location",
+    params(LocationParams),
+    responses(
+        (status = 200, description = "The location's most recent posts", body = [InstagramPost]),
+        (status = 400, description = "Invalid location ID"),
+        (status = 401, description = "Invalid token"),
+        (status = 403, description = "Feeds are disabled while ALLOWED_USERNAMES is set"),
+        (status = 404, description = "Location not found"),
+        (status = 502, description = "Instagram request failed")
+    )
+)]
+async fn location_handler(req: HttpRequest, query: web::Query<LocationParams>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    if !is_authorized(&req, query.token.as_deref()) {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid_token", "Invalid token");
+    }
+    if state.allowed_usernames.is_some() {
+        return feeds_not_allowed();
+    }
+    
+    let location_id = query.location_id.trim();
+    if location_id.is_empty() || !location_id.chars().all(|c| c.is_ascii_digit()) {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_location_id", "Invalid location ID");
+    }
+    let limit = state.runtime().post_limit(query.limit);
+    
+    let key = format!("location:{}:{}", location_id, limit);
+    feed_response(&state, key, fetch_media_feed(&state, MediaFeed::Location(location_id), limit)).await
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct FollowParams {
+    /// auth token, unless it's sent as `Authorization: Bearer <token>` instead.
+    token: Option<String>,
+    /// the account whose followers or following to list
+    username: String,
+    /// number of accounts to return (default 50, at most 1000), fetched 50 per upstream request
+    limit: Option<usize>,
+    /// end_cursor from a previous response, to continue where it left off
+    after: Option<String>,
+}
+
+// Default and largest number of accounts returned by the follow list endpoints
+const DEFAULT_FOLLOW_LIMIT: usize = 50;
+const MAX_FOLLOW_LIMIT: usize = 1000;
+
+/// Accounts following a user, a page at a time
+#[utoipa::path(
+    get,
+    path = "/api/instagram_followers",
+    params(FollowParams),
+    responses(
+        (status = 200, description = "A page of the user's followers", body = FollowList),
+        (status = 400, description = "Invalid username"),
+        (status = 401, description = "Invalid token"),
+        (status = 403, description = "Username not allowed"),
+        (status = 404, description = "User not found"),
+        (status = 502, description = "Instagram request failed"),
+        (status = 503, description = "SESSION_COOKIE not configured, or server busy")
+    )
+)]
+async fn followers_handler(req: HttpRequest, query: web::Query<FollowParams>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    follow_list_response(&req, query.into_inner(), &state, FollowEdge::Followers).await
+}
+
+/// Accounts a user follows, a page at a time
+#[utoipa::path(
+    get,
+    path = "/api/instagram_following",
+    params(FollowParams),
+    responses(
+        (status = 200, description = "A page of the accounts the user follows", body = FollowList),
+        (status = 400, description = "Invalid username"),
+        (status = 401, description = "Invalid token"),
+        (status = 403, description = "Username not allowed"),
+        (status = 404, description = "User not found"),
+        (status = 502, description = "Instagram request failed"),
+        (status = 503, description = "SESSION_COOKIE not configured, or server busy")
+    )
+)]
+async fn following_handler(req: HttpRequest, query: web::Query<FollowParams>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    follow_list_response(&req, query.into_inner(), &state, FollowEdge::Following).await
+}
+
+// Follow lists aren't cached, but they count against MAX_INFLIGHT and the circuit breaker like profile fetches
+async fn follow_list_response(req: &HttpRequest, query: FollowParams, state: &AppState, edge: FollowEdge) -> HttpResponse {
+    if !is_authorized(req, query.token.as_deref()) {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid_token", "Invalid token");
+    }
+    
+    let Some(cookie) = &state.session_cookie else {
+        return error_response(StatusCode::SERVICE_UNAVAILABLE, "session_required", "Followers and following require SESSION_COOKIE");
+    };
+    
+    let username = normalize_username(&query.username);
+    if !is_valid_username(&username) {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_username", "Invalid username");
+    }
+    if state.allowed_usernames.as_ref().is_some_and(|allowed| !allowed.contains(&username)) {
+        return error_response(StatusCode::FORBIDDEN, "username_not_allowed", "Username not allowed");
+    }
+    
+    let Ok(_permit) = state.inflight.clone().try_acquire_owned() else {
+        return server_busy();
+    };
+    
+    let limit = query.limit.unwrap_or(DEFAULT_FOLLOW_LIMIT).clamp(1, MAX_FOLLOW_LIMIT);
+    
+    match fetch_follow_list(state, cookie, &username, edge, query.after, limit).await {
+        Ok(list) => HttpResponse::Ok()
+            .insert_header((header::CACHE_CONTROL, "no-store"))
+            .json(list),
+        Err(FetchError::NotFound) => error_response(StatusCode::NOT_FOUND, "not_found", "User not found"),
+        Err(e) => error_response(StatusCode::BAD_GATEWAY, "upstream_error", e.to_string()),
+    }
+}
+
+// Serve a feed from the feed cache, or fetch and cache it. Unknown feeds are cached with the
+// negative TTL, transient failures aren't cached, same as profiles and posts.
+async fn feed_response(
+    state: &AppState,
+    key: String,
+    fetch: impl std::future::Future<Output = Result<Vec<InstagramPost>, FetchError>>,
+) -> HttpResponse {
+    let cached = {
+        let now = Instant::now();
+        state.cache_enabled.then(|| state.feed_cache.get_mut(&key))
+            .flatten()
+            .filter(|entry| entry.is_fresh(now))
+            .map(|mut entry| {
+                entry.last_accessed = now;
+                entry.data.clone()
+            })
+    };
+    
+    let result = match cached {
+        Some(result) => {
+            info!("Cache hit for feed: {}", key);
+            result
+        }
+        None => {
+            let result = fetch.await;
+            
+            let ttl = match &result {
+                Ok(_) => Some(FEED_CACHE_TTL),
+                Err(FetchError::NotFound) => Some(state.runtime().negative_cache_ttl),
+                Err(_) => None,
+            };
+            if let Some(ttl) = ttl.filter(|_| state.cache_enabled) {
+                cache_insert(&state.feed_cache, key, CacheEntry::new(result.clone(), state.jittered(ttl)), state.max_cache_entries);
+            }
+            
+            result
+        }
+    };
+    
+    match result {
+        Ok(posts) => HttpResponse::Ok().json(posts),
+        Err(FetchError::NotFound) => error_response(StatusCode::NOT_FOUND, "not_found", "Feed not found"),
+        Err(e) => error_response(StatusCode::BAD_GATEWAY, "upstream_error", e.to_string()),
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Signature that lets /api/image serve a URL without the token, so rewritten URLs work in
+// plain <img> tags. It's an HMAC of the URL keyed with the auth token.
+fn sign_image_url(key: &str, url: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(url.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+fn is_valid_image_signature(url: &str, signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    let mut mac = HmacSha256::new_from_slice(get_auth_token().as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(url.as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+// Rewrites CDN image URLs to signed /api/image URLs, enabled by PROXY_IMAGES.
+// The originals are kept in the matching original_* fields.
+struct ImageProxy<'a> {
+    base_url: &'a str,
+    key: String,
+}
+
+impl<'a> ImageProxy<'a> {
+    fn for_state(state: &'a AppState) -> Option<Self> {
+        state.image_proxy_base_url.as_deref().map(|base_url| ImageProxy {
+            base_url,
+            key: get_auth_token(),
+        })
+    }
+    
+    fn url_for(&self, url: &str) -> String {
+        let query = serde_urlencoded::to_string([("url", url), ("sig", &sign_image_url(&self.key, url))])
+            .unwrap_or_default();
+        format!("{}/api/image?{}", self.base_url, query)
+    }
+    
+    // Swap a URL for its proxied version, returning the original. Empty URLs are left alone.
+    fn rewrite(&self, url: &mut String) -> Option<String> {
+        if url.is_empty() {
+            return None;
+        }
+        let proxied = self.url_for(url);
+        Some(std::mem::replace(url, proxied))
+    }
+    
+    fn rewrite_post(&self, post: &mut InstagramPost) {
+        post.original_image_url = self.rewrite(&mut post.image_url);
+        if let Some(preview) = &mut post.video_preview_url {
+            post.original_video_preview_url = self.rewrite(preview);
+        }
+    }
+    
+    fn rewrite_profile(&self, user: &mut InstagramUserPosts) {
+        user.original_profile_pic_url = self.rewrite(&mut user.profile_pic_url);
+        user.original_profile_pic_url_hd = self.rewrite(&mut user.profile_pic_url_hd);
+        user.posts.iter_mut().for_each(|post| self.rewrite_post(post));
+        user.tagged_posts.iter_mut().for_each(|post| self.rewrite_post(post));
+        user.reels.iter_mut().for_each(|post| self.rewrite_post(post));
+    }
+}
+
+// CDN domains the image proxy will fetch from, so it can't be used as an open proxy
+const IMAGE_PROXY_DOMAINS: &[&str] = &["cdninstagram.com", "fbcdn.net"];
+
+// Whether a URL is an https URL on one of the Instagram CDN domains (or a subdomain)
+fn is_instagram_cdn_url(url: &reqwest::Url) -> bool {
+    url.scheme() == "https"
+        && url.host_str().is_some_and(|host| IMAGE_PROXY_DOMAINS.iter()
+            .any(|domain| host == *domain || host.ends_with(&format!(".{}", domain))))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ImageProxyParams {
+    /// auth token, unless it's sent as `Authorization: Bearer <token>` instead.
+    token: Option<String>,
+    /// an image_url, profile_pic_url etc. from a previous response
+    url: String,
+    /// signature from a PROXY_IMAGES URL, accepted instead of the token
+    sig: Option<String>,
+}
+
+/// Fetch an Instagram CDN image server-side and stream it back, since the CDN refuses hotlinks from other sites
+#[utoipa::path(
+    get,
+    path = "/api/image",
+    params(ImageProxyParams),
+    responses(
+        (status = 200, description = "The image, with the upstream Content-Type"),
+        (status = 400, description = "Invalid or non-Instagram URL"),
+        (status = 401, description = "Invalid token"),
+        (status = 502, description = "Fetching the image failed")
+    )
+)]
+async fn image_proxy_handler(req: HttpRequest, query: web::Query<ImageProxyParams>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    let signed = query.sig.as_deref().is_some_and(|sig| is_valid_image_signature(&query.url, sig));
+    if !signed && !is_authorized(&req, query.token.as_deref()) {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid_token", "Invalid token");
+    }
+    
+    let url = match reqwest::Url::parse(query.url.trim()) {
+        Ok(url) if is_instagram_cdn_url(&url) => url,
+        _ => return error_response(StatusCode::BAD_REQUEST, "invalid_image_url", "Only Instagram CDN URLs can be proxied"),
+    };
+    
+    let resp = match state.client.get(url)
+        .header("User-Agent", random_user_agent(&state))
+        .header("Referer", "https://www.instagram.com/")
+        .timeout(state.runtime().request_timeout)
+        .send()
+        .await
+    {
+        Ok(resp) => resp,
+        Err(e) => {
+            warn!("Image proxy request failed: {}", e);
+            return error_response(StatusCode::BAD_GATEWAY, "upstream_error", "Image request failed");
+        }
+    };
+    
+    // A redirect off the CDN would turn this into an open proxy after all
+    if !is_instagram_cdn_url(resp.url()) {
+        warn!("Image proxy was redirected off the CDN to {}", resp.url());
+        return error_response(StatusCode::BAD_GATEWAY, "upstream_error", "Image request failed");
+    }
+    if !resp.status().is_success() {
+        return error_response(StatusCode::BAD_GATEWAY, "upstream_error", format!("Unexpected upstream status {}", resp.status().as_u16()));
+    }
+    if resp.content_length().is_some_and(|len| len > state.max_response_bytes as u64) {
+        return error_response(StatusCode::BAD_GATEWAY, "response_too_large", FetchError::ResponseTooLarge.to_string());
+    }
+    
+    let mut response = HttpResponse::Ok();
+    response.content_type(
+        resp.headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("application/octet-stream")
+            .to_string(),
+    );
+    if let Some(cache_control) = resp.headers().get(reqwest::header::CACHE_CONTROL).and_then(|v| v.to_str().ok()) {
+        response.insert_header((header::CACHE_CONTROL, cache_control.to_string()));
+    }
+    
+    // Stream the body through, cutting it off if it grows past the size limit
+    let max_bytes = state.max_response_bytes;
+    let mut streamed = 0;
+    let body = resp.bytes_stream().map(move |chunk| {
+        let chunk = chunk.map_err(actix_web::error::ErrorBadGateway)?;
+        streamed += chunk.len();
+        if streamed > max_bytes {
+            return Err(actix_web::error::ErrorBadGateway(FetchError::ResponseTooLarge.to_string()));
+        }
+        Ok(chunk)
+    });
+    
+    response.streaming(body)
+}
+
+tokio::task_local! {
+    // ID of the inbound request being handled, included in every log line it produces
+    static REQUEST_ID: String;
+    // Upstream timeout requested with timeout_ms, used by upstream_timeout instead of the configured one
+    static UPSTREAM_TIMEOUT: Duration;
+    // Queue priority of the profile fetch being run, used by upstream_call for each of its requests
+    static UPSTREAM_PRIORITY: Priority;
+}
+
+// The request ID of the request currently being handled, if any
+fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+// Spawn a background task that keeps the current request ID, so its logs can be traced back to the request
+fn spawn_with_request_id<F: std::future::Future + 'static>(task: F) -> actix_web::rt::task::JoinHandle<F::Output> {
+    match current_request_id() {
+        Some(request_id) => actix_web::rt::spawn(REQUEST_ID.scope(request_id, task)),
+        None => actix_web::rt::spawn(task),
+    }
+}
+
+// Reuse the client's X-Request-Id when it looks sane, otherwise generate a new one.
+// Client values end up in logs, so they're limited to short printable ASCII.
+fn request_id_for(req: &ServiceRequest) -> String {
+    req.headers()
+        .get("X-Request-Id")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty() && v.len() <= 128 && v.chars().all(|c| c.is_ascii_graphic()))
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+// Middleware that runs each request inside its request ID scope and echoes the ID back in X-Request-Id
+async fn with_request_id(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let request_id = request_id_for(&req);
+    let header_value = actix_web::http::header::HeaderValue::from_str(&request_id).ok();
+    
+    // With Sentry enabled, panics while handling the request are reported with its ID
+    let handled = REQUEST_ID.scope(request_id.clone(), next.call(req));
+    let mut response = if sentry::Hub::main().client().is_some() {
+        let hub = Arc::new(sentry::Hub::new_from_top(sentry::Hub::main()));
+        hub.configure_scope(|scope| scope.set_tag("request_id", &request_id));
+        handled.bind_hub(hub).await?
+    } else {
+        handled.await?
+    };
+    
+    if let Some(value) = header_value {
+        response.headers_mut().insert(actix_web::http::header::HeaderName::from_static("x-request-id"), value);
+    }
+    
+    Ok(response)
+}
+
+// Payload POSTed to WEBHOOK_URL when a watched account publishes new posts
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    username: &'a str,
+    posts: Vec<&'a InstagramPost>,
+}
+
+// Periodically re-fetch the watched usernames and push any posts we haven't seen before to the webhook.
+// The first poll of each account only records a baseline, so existing posts aren't announced on startup.
+async fn run_webhook_notifier(state: Arc<AppState>, webhook_url: String, usernames: Vec<String>, interval: Duration) {
+    let mut seen: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut ticker = actix_web::rt::time::interval(interval);
+    
+    loop {
+        ticker.tick().await;
+        
+        for username in &usernames {
+            let data = match fetch_instagram_posts(&state, username, &FetchOptions::background(&state)).await {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Webhook poll failed for user {}: {}", username, e);
+                    continue;
+                }
+            };
+            
+            let is_baseline = !seen.contains_key(username);
+            let seen_shortcodes = seen.entry(username.clone()).or_default();
+            
+            let new_posts: Vec<&InstagramPost> = data.posts.iter()
+                .filter(|post| !post.shortcode.is_empty() && !seen_shortcodes.contains(&post.shortcode))
+                .collect();
+            
+            seen_shortcodes.extend(new_posts.iter().map(|post| post.shortcode.clone()));
+            
+            if is_baseline || new_posts.is_empty() {
+                continue;
+            }
+            
+            info!("Sending {} new post(s) for user {} to webhook", new_posts.len(), username);
+            
+            let payload = WebhookPayload {
+                username,
+                posts: new_posts,
+            };
+            
+            match state.client.post(&webhook_url).json(&payload).send().await {
+                Ok(resp) if resp.status().is_success() => {}
+                Ok(resp) => warn!("Webhook returned status {} for user {}", resp.status(), username),
+                Err(e) => warn!("Failed to call webhook for user {}: {}", username, e),
+            }
+        }
+    }
+}
+
+// Build the rustls config from the PEM files in TLS_CERT_PATH and TLS_KEY_PATH. None when neither
+// is set, an error when only one is or the files can't be loaded, so a typo can't silently fall back to HTTP.
+fn load_tls_config() -> std::io::Result<Option<rustls::ServerConfig>> {
+    let cert_path = config_var("TLS_CERT_PATH").filter(|p| !p.is_empty());
+    let key_path = config_var("TLS_KEY_PATH").filter(|p| !p.is_empty());
+    
+    let (cert_path, key_path) = match (cert_path, key_path) {
+        (None, None) => return Ok(None),
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "TLS_CERT_PATH and TLS_KEY_PATH must be set together",
+            ))
+        }
+    };
+    
+    let tls_error = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    
+    let mut cert_file = std::io::BufReader::new(std::fs::File::open(&cert_path)
+        .map_err(|e| tls_error(format!("Failed to open TLS certificate {}: {}", cert_path, e)))?);
+    let certs = rustls_pemfile::certs(&mut cert_file)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| tls_error(format!("Failed to read TLS certificate {}: {}", cert_path, e)))?;
+    if certs.is_empty() {
+        return Err(tls_error(format!("No certificates found in {}", cert_path)));
+    }
+    
+    let mut key_file = std::io::BufReader::new(std::fs::File::open(&key_path)
+        .map_err(|e| tls_error(format!("Failed to open TLS key {}: {}", key_path, e)))?);
+    let key = rustls_pemfile::private_key(&mut key_file)
+        .map_err(|e| tls_error(format!("Failed to read TLS key {}: {}", key_path, e)))?
+        .ok_or_else(|| tls_error(format!("No private key found in {}", key_path)))?;
+    
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| tls_error(format!("Invalid TLS certificate or key: {}", e)))?;
+    
+    info!("TLS enabled with certificate {}", cert_path);
+    Ok(Some(config))
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    // Log at info level unless RUST_LOG says otherwise, e.g. RUST_LOG=debug for upstream body snippets.
+    // Lines logged while handling a request are tagged with its request ID.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .format(|buf, record| {
+            let timestamp = buf.timestamp();
+            match current_request_id() {
+                Some(request_id) => writeln!(buf, "[{} {:<5} {}] [{}] {}", timestamp, record.level(), record.target(), request_id, record.args()),
+                None => writeln!(buf, "[{} {:<5} {}] {}", timestamp, record.level(), record.target(), record.args()),
+            }
+        })
+        .init();
+    
+    if let Err(e) = load_config_file() {
+        return Err(std::io::Error::new(e.kind(), format!("Failed to read CONFIG_FILE: {}", e)));
+    }
+    
+    // Report panics and failed fetches to Sentry when SENTRY_DSN is set. Without it the client
+    // is never created, so reporting costs nothing.
+    let _sentry = config_var("SENTRY_DSN").filter(|dsn| !dsn.is_empty()).map(|dsn| {
+        info!("Reporting errors to Sentry");
+        let mut options = sentry::ClientOptions::default();
+        options.release = sentry::release_name!();
+        sentry::init((dsn, options))
+    });
+    
+    // With STRICT_AUTH, running on the well-known default token is a configuration error rather than a warning
+    if get_env_flag("STRICT_AUTH") && config_var("AUTH_TOKEN").is_none_or(|token| token.is_empty()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "STRICT_AUTH is set but AUTH_TOKEN is not, refusing to start with the default token",
+        ));
+    }
+    
+    let runtime = RuntimeConfig::load();
+    runtime.log();
+        
+    // Caching is on unless CACHE_ENABLED is explicitly turned off
+    let cache_enabled = config_var("CACHE_ENABLED")
+        .is_none_or(|value| !matches!(value.trim().to_ascii_lowercase().as_str(), "false" | "0" | "no" | "off"));
+    if !cache_enabled {
+        info!("Caching disabled, every request is fetched from Instagram");
+    }
+    
+    let cache_mode = get_cache_mode();
+    let max_stale = Duration::from_secs(get_env_number("SWR_MAX_STALE_SECONDS", 300));
+    if cache_mode == CacheMode::StaleWhileRevalidate {
+        info!("Cache mode: stale-while-revalidate (serving up to {}s past expiry)", max_stale.as_secs());
+    }
+    
+    let max_inflight = get_env_number("MAX_INFLIGHT", 100);
+    info!("Max in-flight requests: {}", max_inflight);
+    
+    let image_proxy_base_url = get_env_flag("PROXY_IMAGES").then(|| {
+        config_var("PUBLIC_BASE_URL").unwrap_or_default().trim().trim_end_matches('/').to_string()
+    });
+    if let Some(base_url) = &image_proxy_base_url {
+        info!("Rewriting image URLs to {}/api/image", base_url);
+    }
+    
+    let max_response_bytes = get_env_number("MAX_RESPONSE_BYTES", 10 * 1024 * 1024);
+    info!("Max upstream response size: {} bytes", max_response_bytes);
+    
+    let max_cache_entries = get_env_number("MAX_CACHE_ENTRIES", 10000);
+    info!("Max cache entries: {}", max_cache_entries);
+    
+    let session_cookie = config_var("SESSION_COOKIE").filter(|c| !c.is_empty());
+    if session_cookie.is_none() {
+        info!("SESSION_COOKIE not set, stories and the highlights tray will not be available");
+    }
+    
+    let allow_raw = get_env_flag("ALLOW_RAW");
+    if allow_raw {
+        info!("Raw Instagram responses enabled for raw=true requests");
+    }
+    
+    let html_fallback = get_env_flag("HTML_FALLBACK");
+    if html_fallback {
+        info!("HTML fallback enabled for blocked profile API requests");
+    }
+    
+    let circuit_breaker = CircuitBreaker::new(
+        get_env_number("CIRCUIT_BREAKER_THRESHOLD", 5),
+        Duration::from_secs(get_env_number("CIRCUIT_BREAKER_WINDOW_SECONDS", 60)),
+        Duration::from_secs(get_env_number("CIRCUIT_BREAKER_COOLDOWN_SECONDS", 300)),
+    );
+    info!(
+        "Circuit breaker: opens after {} blocks within {}s, cooldown {}s",
+        circuit_breaker.threshold,
+        circuit_breaker.window.as_secs(),
+        circuit_breaker.cooldown.as_secs()
+    );
+    
+    let upstream_queue = UpstreamQueue::new(get_env_number("UPSTREAM_CONCURRENCY", 10));
+    info!("Running at most {} upstream requests at once, interactive ones first", upstream_queue.slots);
+    
+    let instagram_base_url = config_var("INSTAGRAM_BASE_URL")
+        .map(|url| url.trim().trim_end_matches('/').to_string())
+        .filter(|url| !url.is_empty())
+        .unwrap_or_else(|| "https://www.instagram.com".to_string());
+    if instagram_base_url != "https://www.instagram.com" {
+        warn!("Sending upstream requests to {} instead of Instagram", instagram_base_url);
+    }
+    
+    let backup_upstream = config_var("BACKUP_UPSTREAM_URL")
+        .map(|url| url.trim().trim_end_matches('/').to_string())
+        .filter(|url| !url.is_empty())
+        .map(|url| BackupUpstream {
+            url,
+            token: config_var("BACKUP_UPSTREAM_TOKEN").filter(|t| !t.is_empty()),
+        });
+    if let Some(backup) = &backup_upstream {
+        info!("Falling back on {} when blocked", backup.url);
+    }
+    
+    let rate_limit_retries = get_env_number_or_zero("RATE_LIMIT_RETRIES", 0);
+    let max_retry_after = Duration::from_secs(get_env_number("MAX_RETRY_AFTER_SECONDS", 60));
+    info!("Retrying rate-limited fetches up to {} time(s), waiting at most {}s", rate_limit_retries, max_retry_after.as_secs());
+    
+    let allowed_usernames: Option<HashSet<String>> = config_var("ALLOWED_USERNAMES")
+        .filter(|list| !list.trim().is_empty())
+        .map(|list| {
+            list.split(',')
+                .map(normalize_username)
+                .filter(|s| is_valid_username(s))
+                .collect()
+        });
+    if let Some(allowed) = &allowed_usernames {
+        info!("Only fetching the {} username(s) in ALLOWED_USERNAMES", allowed.len());
+    }
+    
+    let selftest_username = normalize_username(&config_var("SELFTEST_USERNAME").unwrap_or_else(|| "instagram".to_string()));
+    
+    let extra_headers = get_extra_headers();
+    if !extra_headers.is_empty() {
+        let names: Vec<&str> = extra_headers.keys().map(|k| k.as_str()).collect();
+        info!("Sending extra upstream headers: {}", names.join(", "));
+    }
+    
+    let user_agents = get_user_agents();
+    info!("Rotating between {} User-Agent(s)", user_agents.len());
+    
+    // Connection pool limits, reqwest's defaults unless POOL_MAX_IDLE or POOL_IDLE_TIMEOUT_SECONDS are set.
+    // Keeping fewer, shorter-lived idle connections helps behind proxies that drop them early.
+    let pool_max_idle = get_env_number_or_zero("POOL_MAX_IDLE", usize::MAX);
+    let pool_idle_timeout = Duration::from_secs(get_env_number("POOL_IDLE_TIMEOUT_SECONDS", 90));
+    info!(
+        "Connection pool: {} idle connection(s) per host, idle timeout {}s",
+        if pool_max_idle == usize::MAX { "unlimited".to_string() } else { pool_max_idle.to_string() },
+        pool_idle_timeout.as_secs()
+    );
+    
+    // Initialize client. Its timeout is only a fallback, the per-request timeout above overrides it.
+    // Same goes for the User-Agent, Instagram requests pick a random one from the list.
+    let client = Client::builder()
+        .user_agent(&user_agents[0])
+        .timeout(Duration::from_secs(30))
+        .pool_max_idle_per_host(pool_max_idle)
+        .pool_idle_timeout(pool_idle_timeout)
         .build()
         .expect("Failed to build HTTP client");
         
     // Initialize app state with cache
     let app_state = Arc::new(AppState {
-        cache: Mutex::new(HashMap::new()),
+        cache: Cache::new(),
+        post_cache: Cache::new(),
+        feed_cache: Cache::new(),
         client,
+        cache_enabled,
+        runtime: RwLock::new(Arc::new(runtime)),
+        cache_mode,
+        max_stale,
+        max_cache_entries,
+        user_agents,
+        extra_headers,
+        session_cookie,
+        html_fallback,
+        circuit_breaker,
+        upstream_queue,
+        allow_raw,
+        image_proxy_base_url,
+        max_response_bytes,
+        inflight: Arc::new(Semaphore::new(max_inflight)),
+        in_flight: DashMap::new(),
+        www_claim: RwLock::new("0".to_string()),
+        selftest_username,
+        allowed_usernames,
+        default_avatar_url: config_var("DEFAULT_AVATAR_URL").filter(|url| !url.trim().is_empty()),
+        backup_upstream,
+        rate_limit_retries,
+        max_retry_after,
+        instagram_base_url,
+        upstream_rate_limit: RwLock::new(None),
+        posts_requests: AtomicU64::new(0),
+        cache_hits: AtomicU64::new(0),
+        cache_misses: AtomicU64::new(0),
     });
     
-    // Bind the server to all interfaces on port 8080 for container compatibility
-    HttpServer::new(move || {
+    actix_web::rt::spawn(run_cache_sweeper(app_state.clone()));
+    actix_web::rt::spawn(run_stats_logger(
+        app_state.clone(),
+        Duration::from_secs(get_env_number("STATS_INTERVAL_SECONDS", 300)),
+    ));
+    
+    // With WARM_ON_START the cache is filled before we start listening, so even the first requests hit it
+    let warm_usernames = app_state.runtime().warm_usernames.clone();
+    if cache_enabled && !warm_usernames.is_empty() && get_env_flag("WARM_ON_START") {
+        let started = Instant::now();
+        let usernames: Vec<&String> = warm_usernames.iter().collect();
+        let failures = warm_cache(&app_state, &usernames).await;
+        info!(
+            "Startup cache warm finished in {}ms, {} of {} user(s) cached",
+            started.elapsed().as_millis(),
+            usernames.len() - failures,
+            usernames.len()
+        );
+    }
+    
+    // Keep the hot accounts from WARM_USERNAMES in the cache. Always running while caching is on, since a reload may add some.
+    if cache_enabled {
+        actix_web::rt::spawn(run_cache_warmer(app_state.clone()));
+    }
+    
+    // Start the webhook notifier when a callback URL and accounts to watch are configured
+    if let Some(webhook_url) = config_var("WEBHOOK_URL") {
+        let usernames: Vec<String> = config_var("WEBHOOK_USERNAMES")
+            .unwrap_or_default()
+            .split(',')
+            .map(normalize_username)
+            .filter(|s| is_valid_username(s))
+            .collect();
+        let interval = Duration::from_secs(get_env_number("WEBHOOK_INTERVAL_SECONDS", 300));
+        
+        if usernames.is_empty() {
+            warn!("WEBHOOK_URL is set but WEBHOOK_USERNAMES is empty, webhook notifier disabled");
+        } else {
+            info!("Watching {} account(s) for new posts every {}s", usernames.len(), interval.as_secs());
+            actix_web::rt::spawn(run_webhook_notifier(app_state.clone(), webhook_url, usernames, interval));
+        }
+    }
+    
+    // Bind to all interfaces on port 8080 by default for container compatibility.
+    // BIND_ADDRESS overrides it and may list several addresses, e.g. "0.0.0.0:8080,[::]:8080" for dual-stack.
+    let bind_addresses: Vec<String> = config_var("BIND_ADDRESS")
+        .unwrap_or_else(|| "0.0.0.0:8080".to_string())
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+    
+    let mut server = HttpServer::new(move || {
         App::new()
+            // Compress every response (gzip/brotli/zstd) based on the client's Accept-Encoding
+            .wrap(Compress::default())
+            .wrap(from_fn(with_request_id))
+            .app_data(web::QueryConfig::default().error_handler(invalid_parameters))
+            .app_data(web::JsonConfig::default().error_handler(invalid_parameters))
             .app_data(web::Data::new(app_state.clone()))
             .route("/api/instagram_posts", web::get().to(instagram_handler))
-    })
-    .bind("0.0.0.0:8080")?
-    .run()
-    .await
+            .route("/api/instagram_posts", web::post().to(instagram_post_handler))
+            .route("/api/instagram_posts/stream", web::get().to(instagram_stream_handler))
+            .route("/api/instagram_post", web::get().to(shortcode_handler))
+            .route("/api/image", web::get().to(image_proxy_handler))
+            .route("/api/instagram_hashtag", web::get().to(hashtag_handler))
+            .route("/api/instagram_followers", web::get().to(followers_handler))
+            .route("/api/instagram_following", web::get().to(following_handler))
+            .route("/api/instagram_location", web::get().to(location_handler))
+            .route("/api/validate", web::get().to(validate_handler))
+            .route("/api/cache", web::get().to(cache_summary_handler))
+            .route("/api/cache", web::delete().to(cache_clear_handler))
+            .route("/api/config/reload", web::post().to(config_reload_handler))
+            .route("/api/selftest", web::get().to(selftest_handler))
+            .route("/health", web::get().to(health_handler))
+            .route("/openapi.json", web::get().to(openapi_handler))
+    });
+    
+    // Serve HTTPS directly when TLS_CERT_PATH and TLS_KEY_PATH are set
+    let tls_config = load_tls_config()?;
+    let scheme = if tls_config.is_some() { "https" } else { "http" };
+    
+    for address in &bind_addresses {
+        let bound = match &tls_config {
+            Some(config) => server.bind_rustls_0_23(address, config.clone()),
+            None => server.bind(address),
+        };
+        server = bound.map_err(|e| {
+            std::io::Error::new(e.kind(), format!("Failed to bind to {}: {}", address, e))
+        })?;
+    }
+    
+    for addr in server.addrs() {
+        info!("Starting Instagram API server on {}://{}", scheme, addr);
+    }
+    
+    server.run().await
 }